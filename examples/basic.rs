@@ -1,16 +1,16 @@
 use regex::Regex;
-use vk_bot::{Bot, Core, Event, Handler};
+use vk_bot::{Bot, Core, ErrorHandler, Event, Handler};
 
 fn main() {
     // A simple closure for convenience...
     let simple_handler = |message| {
-        // ...that returns a handler!
-        Handler::new(move |ctx| {
+        // ...that returns an async handler!
+        Handler::new_async(move |mut ctx| async move {
             // Set the message...
             ctx.response().set_message(message);
 
-            // ...and send it, printing the error if not successful.
-            eprintln!("{:?}", ctx.send());
+            // ...and send it, returning the error if not successful.
+            ctx.send().await
         })
     };
 
@@ -25,7 +25,14 @@ fn main() {
         .on(
             Event::NoMatch,
             simple_handler("I don't understand this message..."),
-        );
+        )
+        // Will be used if any of the handlers above returns an error:
+        .on_error(ErrorHandler::new_async(|e, mut ctx| async move {
+            eprintln!("handler failed: {}", e);
+
+            ctx.response().set_message("Something went wrong, sorry!");
+            let _ = ctx.send().await;
+        }));
 
     Bot::new(
         "your vk token",                   // VK token
@@ -35,5 +42,6 @@ fn main() {
         12345,                             // Port
         core,
     )
-    .start();
+    .run()
+    .expect("failed to run bot");
 }
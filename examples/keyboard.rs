@@ -6,9 +6,9 @@ use vk_bot::{
 fn main() {
     // Simple handler, see `examples/basic.rs` for more details.
     let simple_handler = |message| {
-        Handler::new(move |ctx| {
+        Handler::new_async(move |mut ctx| async move {
             ctx.response().set_message(message);
-            eprintln!("{:?}", ctx.send());
+            ctx.send().await
         })
     };
 
@@ -31,11 +31,13 @@ fn main() {
         // Command that will be used if message contains `/keyboard` (without quotes) in the beginning:
         .cmd(
             "keyboard",
-            Handler::new(move |ctx| {
-                ctx.response().set_message("Here you go:");
-                ctx.response().set_keyboard(kbd.clone());
-
-                eprintln!("{:?}", ctx.send());
+            Handler::new_async(move |mut ctx| {
+                let kbd = kbd.clone();
+                async move {
+                    ctx.response().set_message("Here you go:");
+                    ctx.response().set_keyboard(kbd);
+                    ctx.send().await
+                }
             }),
         )
         // Used when the specified payload is found inside of the message:
@@ -51,5 +53,6 @@ fn main() {
         12345,                             // Port
         core,
     )
-    .start();
+    .run()
+    .expect("failed to run bot");
 }
@@ -0,0 +1,19 @@
+//! Helpers for calling the (blocking) VK API from async code.
+
+use rvk::{error::Error, APIClient, Params};
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Calls an API method on a separate thread, so that the async runtime is not
+/// blocked while waiting for the response.
+pub(crate) async fn call_method(
+    api: &Arc<APIClient>,
+    method: &'static str,
+    params: Params,
+) -> Result<Value, Error> {
+    let api = Arc::clone(api);
+
+    tokio::task::spawn_blocking(move || api.call_method(method, params))
+        .await
+        .unwrap_or_else(|e| Err(Error::Other(format!("API call task failed: {}", e))))
+}
@@ -1,93 +1,711 @@
 //! The [`Bot`] struct and server setup.
 
-use crate::{core::Core, request::CallbackAPIRequest};
+use crate::{
+    core::Core,
+    dedup::{Dedup, DedupStore, MemoryDedupStore},
+    longpoll::{LongPoll, LongPollServer},
+    request::CallbackAPIRequest,
+    worker::{Job, QueueFullPolicy, WorkerPool},
+};
 use rocket::{
-    config::{Config, Environment},
-    http::Status,
-    State,
+    config::TlsConfig, data::ByteUnit, fairing::AdHoc, http::Status, serde::json::Json, Build,
+    Config, Rocket, State,
 };
-use rocket_contrib::json::Json;
 use rvk::APIClient;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt::{Display, Formatter},
+    io,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    runtime::{self, Runtime},
+    sync::oneshot,
+    task::{self, JoinError, JoinHandle},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 /// The string `ok` which needs to be sent in response to every Callback API
 /// request.
 const VK_OK: &str = "ok";
 
-/// [`Bot`] represents a chat bot, and hands received requests to [`Core`].
+/// Time to wait before retrying after a Long Poll error.
+const LONG_POLL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A VK community served by a [`Bot`].
 #[derive(Debug)]
-pub struct Bot {
-    api: APIClient,
+pub struct Group {
+    api: Arc<APIClient>,
     confirmation_token: String,
     group_id: i32,
     secret: Option<String>,
-    port: u16,
-    core: Core,
+    core: Option<Arc<Core>>,
 }
 
-impl Bot {
-    /// Creates a new [`Bot`].
-    #[must_use = "the bot does nothing unless started via `.start()`"]
+impl Group {
+    /// Creates a new [`Group`].
+    ///
+    /// Events of this group are handled by the [`Bot`]'s shared [`Core`],
+    /// unless a separate one is set via [`Group::core`].
     pub fn new(
         vk_token: &str,
         confirmation_token: &str,
         group_id: i32,
         secret: Option<String>,
-        port: u16,
-        core: Core,
     ) -> Self {
         Self {
-            api: APIClient::new(vk_token),
+            api: Arc::new(APIClient::new(vk_token)),
             confirmation_token: confirmation_token.into(),
             group_id,
             secret,
-            port,
+            core: None,
+        }
+    }
+
+    /// Sets a separate [`Core`] for handling events of this group.
+    pub fn core(mut self, core: Core) -> Self {
+        self.core = Some(Arc::new(core.build()));
+        self
+    }
+
+    /// Returns the [`rvk::APIClient`] of this group.
+    pub fn api(&self) -> &Arc<APIClient> {
+        &self.api
+    }
+
+    /// Returns the confirmation token of this group.
+    pub fn confirmation_token(&self) -> &String {
+        &self.confirmation_token
+    }
+
+    /// Returns the ID of this group.
+    pub fn group_id(&self) -> i32 {
+        self.group_id
+    }
+
+    /// Returns the secret of this group.
+    pub fn secret(&self) -> Option<String> {
+        self.secret.clone()
+    }
+}
+
+/// Builder for [`Bot`], see [`Bot::builder`].
+#[derive(Debug)]
+pub struct BotBuilder {
+    groups: HashMap<i32, Group>,
+    core: Core,
+    config: Config,
+    path: String,
+    dedup: Option<Dedup>,
+    workers: Option<WorkerPool>,
+}
+
+impl BotBuilder {
+    /// Creates a new [`BotBuilder`] without any communities.
+    ///
+    /// `core` is shared by all groups that do not have their own [`Core`].
+    ///
+    /// By default, the server listens on `0.0.0.0:8000` and handles requests
+    /// at `/`.
+    pub fn new(core: Core) -> Self {
+        Self {
+            groups: Default::default(),
             core,
+            config: Config {
+                address: Ipv4Addr::UNSPECIFIED.into(),
+                ..Config::release_default()
+            },
+            path: "/".into(),
+            dedup: None,
+            workers: None,
         }
     }
 
-    /// Alias for `self.core.handle(req, self.api())`.
-    pub fn handle(&self, req: &CallbackAPIRequest) {
-        self.core.handle(req, self.api());
+    /// Adds a new community.
+    pub fn group(mut self, group: Group) -> Self {
+        match self.groups.entry(group.group_id()) {
+            Entry::Occupied(_) => {
+                panic!("attempt to set up duplicate group `{}`", group.group_id())
+            }
+            Entry::Vacant(entry) => entry.insert(group),
+        };
+
+        self
+    }
+
+    /// Sets the address to bind the server to.
+    pub fn address(mut self, address: IpAddr) -> Self {
+        self.config.address = address;
+        self
     }
 
-    /// Starts this [`Bot`], consuming `self`.
+    /// Sets the port to bind the server to.
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Sets the path at which Callback API requests are handled, e.g.
+    /// `/vk/my_bot`.
     ///
     /// # Panics
-    /// - if Rocket was not able to launch.
-    pub fn start(self) -> ! {
+    /// - if `path` does not start with `/`.
+    pub fn path(mut self, path: &str) -> Self {
+        if !path.starts_with('/') {
+            panic!("path `{}` does not start with `/`", path);
+        }
+
+        self.path = path.into();
+        self
+    }
+
+    /// Enables TLS using the given PEM-encoded certificate chain and private
+    /// key files.
+    pub fn tls(mut self, certs: impl AsRef<Path>, key: impl AsRef<Path>) -> Self {
+        self.config.tls = Some(TlsConfig::from_paths(certs, key));
+        self
+    }
+
+    /// Sets the number of async runtime worker threads.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Sets the keep-alive timeout in seconds. `0` disables keep-alive.
+    pub fn keep_alive(mut self, keep_alive: u32) -> Self {
+        self.config.keep_alive = keep_alive;
+        self
+    }
+
+    /// Sets the maximum size of a JSON request body, in bytes.
+    pub fn json_limit(mut self, bytes: u64) -> Self {
+        self.config.limits = self.config.limits.limit("json", ByteUnit::Byte(bytes));
+        self
+    }
+
+    /// Enables dropping of repeated deliveries of the same event (by
+    /// `event_id`) within `window`, using a [`MemoryDedupStore`].
+    pub fn dedup(self, window: Duration) -> Self {
+        self.dedup_store(MemoryDedupStore::new(), window)
+    }
+
+    /// Enables dropping of repeated deliveries of the same event (by
+    /// `event_id`) within `window`, using the given [`DedupStore`].
+    pub fn dedup_store<S>(mut self, store: S, window: Duration) -> Self
+    where
+        S: DedupStore + 'static,
+    {
+        self.dedup = Some(Dedup::new(store, window));
+        self
+    }
+
+    /// Enables background processing: requests are acknowledged right away,
+    /// and queued to be handled by `workers` background workers, each with a
+    /// queue of `capacity` requests.
+    ///
+    /// Requests from the same peer are handled in order, while requests from
+    /// different peers are handled in parallel. `policy` decides what happens
+    /// when a queue is full.
+    ///
+    /// # Panics
+    /// - if `workers` or `capacity` is zero.
+    pub fn background(mut self, workers: usize, capacity: usize, policy: QueueFullPolicy) -> Self {
+        self.workers = Some(WorkerPool::new(workers, capacity, policy));
+        self
+    }
+
+    /// Builds the [`Bot`].
+    #[must_use = "the bot does nothing unless started via `.start()`"]
+    pub fn build(self) -> Bot {
+        Bot {
+            groups: self.groups,
+            core: Arc::new(self.core.build()),
+            config: self.config,
+            path: self.path,
+            dedup: self.dedup,
+            workers: self.workers,
+            tasks: TaskTracker::new(),
+        }
+    }
+}
+
+/// [`Bot`] represents a chat bot, and hands received requests to [`Core`].
+///
+/// One [`Bot`] may serve several communities, see [`BotBuilder::group`].
+#[derive(Debug)]
+pub struct Bot {
+    groups: HashMap<i32, Group>,
+    core: Arc<Core>,
+    config: Config,
+    path: String,
+    dedup: Option<Dedup>,
+    workers: Option<WorkerPool>,
+    tasks: TaskTracker,
+}
+
+impl Bot {
+    /// Creates a new [`Bot`] serving one community.
+    ///
+    /// See [`Bot::builder`] for more options.
+    #[must_use = "the bot does nothing unless started via `.start()`"]
+    pub fn new(
+        vk_token: &str,
+        confirmation_token: &str,
+        group_id: i32,
+        secret: Option<String>,
+        port: u16,
+        core: Core,
+    ) -> Self {
+        Self::builder(core)
+            .group(Group::new(vk_token, confirmation_token, group_id, secret))
+            .port(port)
+            .build()
+    }
+
+    /// Creates a new [`BotBuilder`].
+    ///
+    /// `core` is shared by all groups that do not have their own [`Core`].
+    pub fn builder(core: Core) -> BotBuilder {
+        BotBuilder::new(core)
+    }
+
+    /// Handles a request of one of this [`Bot`]'s groups, using that group's
+    /// [`Core`] and [`rvk::APIClient`].
+    ///
+    /// Requests of unknown groups and repeated deliveries (see
+    /// [`BotBuilder::dedup`]) are ignored.
+    pub async fn handle(&self, req: &CallbackAPIRequest) {
+        match self.groups.get(&req.group_id()) {
+            Some(group) if self.is_new(req) => self.core_of(group).handle(req, group.api()).await,
+            Some(_) => {}
+            None => debug!("ignored a request with unknown `group_id`"),
+        }
+    }
+
+    /// Handles a request of the given group, either right away or, if
+    /// [`BotBuilder::background`] is used, by queueing it.
+    ///
+    /// Returns an error status if the request was rejected because the queue
+    /// is full, or if handling panicked. The event is then forgotten by
+    /// deduplication, so that a repeated delivery is handled.
+    async fn dispatch(&self, group: &Group, req: CallbackAPIRequest) -> Result<(), Status> {
+        let group_id = req.group_id();
+        let event_id = req.event_id().map(String::from);
+
+        let result = self.dispatch_inner(group, req).await;
+
+        if let (Err(_), Some(dedup), Some(event_id)) = (&result, &self.dedup, &event_id) {
+            dedup.forget(group_id, event_id);
+        }
+        result
+    }
+
+    /// Handles or queues a request, see [`Bot::dispatch`].
+    async fn dispatch_inner(&self, group: &Group, req: CallbackAPIRequest) -> Result<(), Status> {
+        let core = Arc::clone(self.core_of(group));
+        let api = Arc::clone(group.api());
+
+        if let Some(workers) = &self.workers {
+            return if workers.enqueue(Job::new(core, api, req)).await {
+                Ok(())
+            } else {
+                Err(Status::ServiceUnavailable)
+            };
+        }
+
+        // Handle the request in a tracked task, so that graceful shutdown
+        // waits for it even if the connection is closed.
+        self.tasks
+            .spawn(async move { core.handle(&req, &api).await })
+            .await
+            .map_err(|e| {
+                error!("request handling failed: {}", e);
+                Status::InternalServerError
+            })
+    }
+
+    /// Starts the background workers, if any.
+    fn start_workers(&self) {
+        if let Some(workers) = &self.workers {
+            workers.start(&self.tasks);
+        }
+    }
+
+    /// Returns `false` if the request is a repeated delivery of an event.
+    fn is_new(&self, req: &CallbackAPIRequest) -> bool {
+        match &self.dedup {
+            Some(dedup) if !dedup.is_new(req) => {
+                debug!(
+                    "dropped a repeated delivery of event `{}`",
+                    req.event_id().unwrap_or_default()
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns the [`Core`] which handles events of the given group.
+    fn core_of<'a>(&'a self, group: &'a Group) -> &'a Arc<Core> {
+        group.core.as_ref().unwrap_or(&self.core)
+    }
+
+    /// Returns the [`Core`]s handling requests: the core of the bot and the
+    /// cores of the groups.
+    fn cores(&self) -> Vec<Arc<Core>> {
+        let groups = self.groups.values().filter_map(|group| group.core.clone());
+        std::iter::once(Arc::clone(&self.core))
+            .chain(groups)
+            .collect()
+    }
+
+    /// Starts this [`Bot`] in the background on the current async runtime,
+    /// consuming `self`.
+    ///
+    /// Returns once the server is listening for requests. Use the returned
+    /// [`BotHandle`] to wait for the server to stop (e.g. via Ctrl-C) or to
+    /// shut it down.
+    pub async fn launch(self) -> Result<BotHandle, BotError> {
         info!("starting bot...");
 
-        let err = rocket::custom(
-            Config::build(Environment::Production)
-                .address("0.0.0.0")
-                .port(self.port)
-                .unwrap(),
-        )
-        .mount("/", routes![post, get])
-        .manage(self)
-        .launch();
+        let tasks = self.tasks.clone();
+        let cores = self.cores();
+        self.start_workers();
+
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let rocket = self
+            .rocket()
+            .attach(AdHoc::on_liftoff("Bot ready", |rocket| {
+                let port = rocket.config().port;
+                Box::pin(async move {
+                    let _ = ready_tx.send(port);
+                })
+            }))
+            .ignite()
+            .await?;
+
+        let stop = CancellationToken::new();
+        for core in &cores {
+            core.attach(&tasks, &stop);
+        }
 
-        panic!("{}", err);
+        let shutdown = rocket.shutdown();
+        tokio::spawn({
+            let stop = stop.clone();
+            async move {
+                stop.cancelled().await;
+                shutdown.notify();
+            }
+        });
+
+        let mut server = tokio::spawn(async move {
+            rocket.launch().await?;
+            Ok(())
+        });
+
+        let port = tokio::select! {
+            Ok(port) = ready_rx => port,
+            result = &mut server => return match result {
+                Ok(Ok(())) => Err(BotError::Stopped),
+                Ok(Err(e)) => Err(e),
+                Err(e) => Err(BotError::Task(e)),
+            },
+        };
+
+        info!("bot started on port {}", port);
+
+        Ok(BotHandle {
+            stop,
+            server,
+            tasks,
+            cores,
+            port: Some(port),
+        })
     }
 
-    /// Returns the global [`rvk::APIClient`] which is used in this [`Bot`].
-    pub fn api(&self) -> &APIClient {
-        &self.api
+    /// Starts this [`Bot`] in the background on its own async runtime,
+    /// consuming `self`. See [`Bot::launch`].
+    ///
+    /// This method must not be called from within an async runtime.
+    pub fn start(self) -> Result<BlockingBotHandle, BotError> {
+        let runtime = self.runtime()?;
+        let handle = runtime.block_on(self.launch())?;
+        Ok(BlockingBotHandle { runtime, handle })
     }
 
-    /// Returns the confirmation token stored in this [`Bot`].
-    pub fn confirmation_token(&self) -> &String {
-        &self.confirmation_token
+    /// Starts this [`Bot`] and blocks until it is stopped (e.g. via Ctrl-C).
+    ///
+    /// Alias for `self.start()?.wait()`.
+    pub fn run(self) -> Result<(), BotError> {
+        self.start()?.wait()
     }
 
-    /// Returns the group ID stored in this [`Bot`].
-    pub fn group_id(&self) -> i32 {
-        self.group_id
+    /// Creates the async runtime with the configured number of workers.
+    fn runtime(&self) -> Result<Runtime, BotError> {
+        runtime::Builder::new_multi_thread()
+            .worker_threads(self.config.workers)
+            .enable_all()
+            .build()
+            .map_err(BotError::Runtime)
     }
 
-    /// Returns the secret stored in this [`Bot`].
-    pub fn secret(&self) -> Option<String> {
-        self.secret.clone()
+    /// Creates the Rocket instance serving this [`Bot`].
+    fn rocket(self) -> Rocket<Build> {
+        rocket::custom(self.config.clone())
+            .mount(self.path.clone(), routes![post, get])
+            .manage(self)
+    }
+
+    /// Starts this [`Bot`] in the background on the current async runtime
+    /// using the Bots Long Poll API instead of the Callback API server,
+    /// consuming `self`. Every group is polled concurrently.
+    ///
+    /// Long Poll must be enabled in the community settings. Confirmation
+    /// token, secret and server settings are not used in this mode.
+    ///
+    /// Returns once the Long Poll server information is received for every
+    /// group. Errors that happen while polling afterwards are logged, and
+    /// polling is retried after a short delay.
+    ///
+    /// Long Poll updates are never delivered again, so updates refused by
+    /// [`QueueFullPolicy::Reject`] are dropped with a warning, as with
+    /// [`QueueFullPolicy::Drop`].
+    pub async fn launch_long_poll(self) -> Result<BotHandle, BotError> {
+        info!("starting bot (long poll)...");
+
+        let tasks = self.tasks.clone();
+        let cores = self.cores();
+        self.start_workers();
+
+        let mut sessions = Vec::new();
+        for (&group_id, group) in &self.groups {
+            let server = LongPollServer::fetch(group.api(), group_id)
+                .await
+                .map_err(BotError::LongPoll)?;
+            sessions.push((group_id, LongPoll::new(server)));
+        }
+
+        let stop = CancellationToken::new();
+        for core in &cores {
+            core.attach(&tasks, &stop);
+        }
+
+        let bot = Arc::new(self);
+
+        let server = tokio::spawn({
+            let stop = stop.clone();
+            async move {
+                let loops: Vec<_> = sessions
+                    .into_iter()
+                    .map(|(group_id, long_poll)| {
+                        let bot = Arc::clone(&bot);
+                        let stop = stop.clone();
+                        tokio::spawn(async move { bot.long_poll(group_id, long_poll, stop).await })
+                    })
+                    .collect();
+
+                for task in loops {
+                    task.await.map_err(BotError::Task)?;
+                }
+
+                Ok(())
+            }
+        });
+
+        Ok(BotHandle {
+            stop,
+            server,
+            tasks,
+            cores,
+            port: None,
+        })
+    }
+
+    /// Starts this [`Bot`] in the background on its own async runtime using
+    /// the Bots Long Poll API, consuming `self`. See [`Bot::launch_long_poll`].
+    ///
+    /// This method must not be called from within an async runtime.
+    pub fn start_long_poll(self) -> Result<BlockingBotHandle, BotError> {
+        let runtime = self.runtime()?;
+        let handle = runtime.block_on(self.launch_long_poll())?;
+        Ok(BlockingBotHandle { runtime, handle })
+    }
+
+    /// Starts this [`Bot`] using the Bots Long Poll API and blocks until it is
+    /// stopped.
+    ///
+    /// Alias for `self.start_long_poll()?.wait()`.
+    pub fn run_long_poll(self) -> Result<(), BotError> {
+        self.start_long_poll()?.wait()
+    }
+
+    /// Receives events of a group via the Bots Long Poll API and handles them,
+    /// until `stop` is cancelled.
+    async fn long_poll(&self, group_id: i32, mut long_poll: LongPoll, stop: CancellationToken) {
+        let group = &self.groups[&group_id];
+        let fetch = || LongPollServer::fetch(group.api(), group_id);
+
+        loop {
+            let result = tokio::select! {
+                _ = stop.cancelled() => break,
+                result = long_poll.poll(fetch) => result,
+            };
+
+            match result {
+                Ok(updates) => {
+                    for update in updates {
+                        if update.group_id() != group_id {
+                            debug!("received a long poll update with invalid `group_id`");
+                        } else if self.is_new(&update) {
+                            // Long Poll never delivers an update again, so an
+                            // update rejected by the worker pool is lost.
+                            let event_id = update.event_id().unwrap_or_default().to_owned();
+                            if let Err(status) = self.dispatch(group, update).await {
+                                warn!("dropped long poll update `{}`: {}", event_id, status);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    tokio::select! {
+                        _ = stop.cancelled() => break,
+                        _ = tokio::time::sleep(LONG_POLL_RETRY_DELAY) => {}
+                    }
+                }
+            }
+        }
+
+        debug!("stopped long polling for group `{}`", group_id);
+    }
+
+    /// Returns the groups served by this [`Bot`].
+    pub fn groups(&self) -> &HashMap<i32, Group> {
+        &self.groups
+    }
+}
+
+/// A handle to a running [`Bot`], see [`Bot::launch`].
+#[derive(Debug)]
+pub struct BotHandle {
+    stop: CancellationToken,
+    server: JoinHandle<Result<(), BotError>>,
+    tasks: TaskTracker,
+    cores: Vec<Arc<Core>>,
+    port: Option<u16>,
+}
+
+impl BotHandle {
+    /// Returns the port the server is listening on, or `None` in Long Poll
+    /// mode.
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Stops accepting new requests, waits for in-flight handlers and pending
+    /// API calls (like [`Context::send`](crate::Context::send)) to finish,
+    /// flushes the sessions (see
+    /// [`SessionStore::flush`](crate::session::SessionStore::flush)), and
+    /// then stops the bot.
+    pub async fn shutdown(self) -> Result<(), BotError> {
+        info!("shutting down bot...");
+        self.stop.cancel();
+        self.wait().await
+    }
+
+    /// Waits until the bot is stopped (e.g. via Ctrl-C), and for in-flight
+    /// handlers and pending API calls to finish. The sessions are flushed
+    /// afterwards.
+    pub async fn wait(self) -> Result<(), BotError> {
+        let result = self.server.await;
+        // Cancel the timeouts of states, see `Core::state_timeout`.
+        self.stop.cancel();
+        self.tasks.close();
+        self.tasks.wait().await;
+
+        let cores = self.cores;
+        let flushed =
+            task::spawn_blocking(move || cores.iter().for_each(|core| core.flush_sessions()));
+        if let Err(e) = flushed.await {
+            error!("failed to flush sessions: {}", e);
+        }
+        info!("bot stopped");
+
+        result.map_err(BotError::Task)?
+    }
+}
+
+/// A handle to a [`Bot`] running on its own async runtime, see [`Bot::start`].
+#[derive(Debug)]
+pub struct BlockingBotHandle {
+    runtime: Runtime,
+    handle: BotHandle,
+}
+
+impl BlockingBotHandle {
+    /// Returns the port the server is listening on, or `None` in Long Poll
+    /// mode.
+    pub fn port(&self) -> Option<u16> {
+        self.handle.port()
+    }
+
+    /// Blocking version of [`BotHandle::shutdown`].
+    ///
+    /// This method must not be called from within an async runtime.
+    pub fn shutdown(self) -> Result<(), BotError> {
+        let Self { runtime, handle } = self;
+        runtime.block_on(handle.shutdown())
+    }
+
+    /// Blocking version of [`BotHandle::wait`].
+    ///
+    /// This method must not be called from within an async runtime.
+    pub fn wait(self) -> Result<(), BotError> {
+        let Self { runtime, handle } = self;
+        runtime.block_on(handle.wait())
+    }
+}
+
+/// Error type for starting and running a [`Bot`].
+#[derive(Debug)]
+pub enum BotError {
+    /// The async runtime could not be created.
+    Runtime(io::Error),
+    /// Rocket failed to launch or to run the server.
+    Launch(Box<rocket::Error>),
+    /// Long Poll server information could not be requested.
+    LongPoll(rvk::error::Error),
+    /// A background task of the bot failed.
+    Task(JoinError),
+    /// The server stopped before it started listening for requests.
+    Stopped,
+}
+
+impl Display for BotError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            BotError::Runtime(e) => write!(f, "failed to create async runtime: {}", e),
+            BotError::Launch(e) => write!(f, "failed to launch server: {}", e),
+            BotError::LongPoll(e) => {
+                write!(f, "failed to get long poll server information: {}", e)
+            }
+            BotError::Task(e) => write!(f, "bot task failed: {}", e),
+            BotError::Stopped => f.write_str("server stopped before it started"),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+impl From<rocket::Error> for BotError {
+    fn from(e: rocket::Error) -> Self {
+        // Rocket panics when an error is dropped without being inspected.
+        let _ = e.kind();
+        BotError::Launch(Box::new(e))
     }
 }
 
@@ -99,30 +717,35 @@ fn get() -> Status {
     Status::MethodNotAllowed
 }
 
-/// Handles `POST` requests by first checking that secret and group ID are
+/// Handles `POST` requests by first checking that group ID and secret are
 /// correct, and then responds with either confirmation token (if that is what
 /// was requested) or [`VK_OK`] in the other case.
 #[post("/", format = "json", data = "<data>")]
-fn post(data: Json<CallbackAPIRequest>, state: State<Bot>) -> Result<String, Status> {
-    let bot = &*state;
+async fn post(data: Json<CallbackAPIRequest>, state: &State<Bot>) -> Result<String, Status> {
+    let bot = state.inner();
 
-    match &data {
-        x if x.secret() != bot.secret() => {
-            debug!("received a POST request with invalid `secret`");
-            Err(Status::Forbidden)
-        }
-        x if x.group_id() != bot.group_id() => {
+    let group = match bot.groups().get(&data.group_id()) {
+        Some(group) => group,
+        None => {
             debug!("received a POST request with invalid `group_id`");
+            return Err(Status::Forbidden);
+        }
+    };
+
+    match &*data {
+        x if x.secret() != group.secret() => {
+            debug!("received a POST request with invalid `secret`");
             Err(Status::Forbidden)
         }
         x if x.r#type() == "confirmation" => {
             debug!("responded with confirmation token");
-            Ok(bot.confirmation_token().clone())
-        }
-        _ => {
-            bot.handle(&data);
-            Ok(VK_OK.into())
+            Ok(group.confirmation_token().clone())
         }
+        x if !bot.is_new(x) => Ok(VK_OK.into()),
+        _ => bot
+            .dispatch(group, data.into_inner())
+            .await
+            .map(|_| VK_OK.into()),
     }
 }
 
@@ -135,8 +758,8 @@ mod tests {
         assert_eq!(get(), Status::MethodNotAllowed);
     }
 
-    fn post_test(secret: &str, group_id: i32, event: &str) -> Result<String, Status> {
-        let rocket = rocket::ignite().manage(Bot::new(
+    async fn post_test(secret: &str, group_id: i32, event: &str) -> Result<String, Status> {
+        let rocket = rocket::build().manage(Bot::new(
             "vk_token",
             "confirmation_token",
             1,
@@ -150,27 +773,482 @@ mod tests {
                 Some(secret.into()),
                 group_id,
                 event,
+                None,
                 Default::default(),
             )),
-            State::from(&rocket).unwrap(),
+            State::get(&rocket).unwrap(),
         )
+        .await
     }
 
-    #[test]
-    fn post_invalid_secret_returns_403() {
-        assert_eq!(post_test("wrong_secret", 1, ""), Err(Status::Forbidden));
+    #[tokio::test]
+    async fn post_invalid_secret_returns_403() {
+        assert_eq!(
+            post_test("wrong_secret", 1, "").await,
+            Err(Status::Forbidden)
+        );
     }
 
-    #[test]
-    fn post_invalid_group_id_returns_403() {
-        assert_eq!(post_test("secret", 1337, ""), Err(Status::Forbidden));
+    #[tokio::test]
+    async fn post_invalid_group_id_returns_403() {
+        assert_eq!(post_test("secret", 1337, "").await, Err(Status::Forbidden));
     }
 
-    #[test]
-    fn post_confirmation_returns_confirmation_token() {
+    #[tokio::test]
+    async fn post_confirmation_returns_confirmation_token() {
         assert_eq!(
-            post_test("secret", 1, "confirmation"),
+            post_test("secret", 1, "confirmation").await,
             Ok("confirmation_token".to_string())
         );
     }
+
+    mod multi {
+        use super::*;
+        use crate::{core::Event, request::Object, Handler};
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Mutex,
+        };
+
+        fn core_sender(
+            tx: &Arc<Mutex<mpsc::Sender<(&'static str, i32)>>>,
+            name: &'static str,
+        ) -> Core {
+            let tx = Arc::clone(tx);
+
+            Core::new().on(
+                Event::MessageDeny,
+                Handler::new(move |ctx| {
+                    tx.lock()
+                        .expect("failed to lock Mutex")
+                        .send((name, ctx.group_id()))
+                        .expect("failed to send core name");
+                }),
+            )
+        }
+
+        fn request(secret: &str, group_id: i32, event: &str) -> Json<CallbackAPIRequest> {
+            Json(CallbackAPIRequest::new(
+                Some(secret.into()),
+                group_id,
+                event,
+                None,
+                Object::new(None, Some(1), None, None, None, None, Default::default()),
+            ))
+        }
+
+        #[tokio::test]
+        async fn routes_by_group_id() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+
+            let rocket = rocket::build().manage(
+                Bot::builder(core_sender(&tx, "shared"))
+                    .group(Group::new(
+                        "token_1",
+                        "confirmation_1",
+                        1,
+                        Some("secret_1".into()),
+                    ))
+                    .group(
+                        Group::new("token_2", "confirmation_2", 2, Some("secret_2".into()))
+                            .core(core_sender(&tx, "own")),
+                    )
+                    .build(),
+            );
+            let state = State::get(&rocket).unwrap();
+
+            assert_eq!(
+                post(request("secret_1", 1, "confirmation"), state).await,
+                Ok("confirmation_1".to_string())
+            );
+            assert_eq!(
+                post(request("secret_2", 2, "confirmation"), state).await,
+                Ok("confirmation_2".to_string())
+            );
+            assert_eq!(
+                post(request("secret_1", 2, "confirmation"), state).await,
+                Err(Status::Forbidden)
+            );
+            assert_eq!(
+                post(request("secret_1", 3, "confirmation"), state).await,
+                Err(Status::Forbidden)
+            );
+
+            assert_eq!(
+                post(request("secret_1", 1, "message_deny"), state).await,
+                Ok(VK_OK.to_string())
+            );
+            assert_eq!(rx.recv().unwrap(), ("shared", 1));
+
+            assert_eq!(
+                post(request("secret_2", 2, "message_deny"), state).await,
+                Ok(VK_OK.to_string())
+            );
+            assert_eq!(rx.recv().unwrap(), ("own", 2));
+        }
+
+        #[tokio::test]
+        async fn drops_repeated_deliveries() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+
+            let rocket = rocket::build().manage(
+                Bot::builder(core_sender(&tx, "shared"))
+                    .group(Group::new("token", "confirmation", 1, None))
+                    .dedup(Duration::from_secs(60))
+                    .build(),
+            );
+            let state = State::get(&rocket).unwrap();
+
+            let request = |event_id: &str| {
+                Json(CallbackAPIRequest::new(
+                    None,
+                    1,
+                    "message_deny",
+                    Some(event_id.into()),
+                    Object::new(None, Some(1), None, None, None, None, Default::default()),
+                ))
+            };
+
+            assert_eq!(post(request("a"), state).await, Ok(VK_OK.to_string()));
+            assert_eq!(post(request("a"), state).await, Ok(VK_OK.to_string()));
+            assert_eq!(post(request("b"), state).await, Ok(VK_OK.to_string()));
+
+            assert_eq!(rx.try_iter().count(), 2);
+        }
+
+        #[tokio::test]
+        async fn handles_redelivery_of_rejected_requests() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Mutex::new(tx);
+
+            let core = Core::new().on(
+                Event::MessageDeny,
+                Handler::new_async(move |ctx| {
+                    let tx = tx.lock().unwrap().clone();
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        tx.send(ctx.object().text().clone().unwrap()).unwrap();
+                    }
+                }),
+            );
+
+            let bot = Bot::builder(core)
+                .group(Group::new("token", "confirmation", 1, None))
+                .dedup(Duration::from_secs(60))
+                .background(1, 1, QueueFullPolicy::Reject)
+                .build();
+            bot.start_workers();
+            let rocket = rocket::build().manage(bot);
+            let state = State::get(&rocket).unwrap();
+
+            let request = |event_id: &str| {
+                Json(CallbackAPIRequest::new(
+                    None,
+                    1,
+                    "message_deny",
+                    Some(event_id.into()),
+                    Object::new(
+                        None,
+                        Some(1),
+                        None,
+                        Some(event_id.into()),
+                        None,
+                        None,
+                        Default::default(),
+                    ),
+                ))
+            };
+
+            // The first request is being handled, the second one waits in the
+            // queue, and the third one is rejected.
+            assert_eq!(post(request("a"), state).await, Ok(VK_OK.to_string()));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            assert_eq!(post(request("b"), state).await, Ok(VK_OK.to_string()));
+            assert_eq!(
+                post(request("c"), state).await,
+                Err(Status::ServiceUnavailable)
+            );
+
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert_eq!(post(request("c"), state).await, Ok(VK_OK.to_string()));
+            assert_eq!(post(request("c"), state).await, Ok(VK_OK.to_string()));
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+        }
+
+        #[tokio::test]
+        async fn handles_redelivery_after_panic() {
+            let panicked = Arc::new(AtomicBool::new(false));
+            let (tx, rx) = mpsc::channel();
+            let tx = Mutex::new(tx);
+
+            let core = Core::new().on(
+                Event::MessageDeny,
+                Handler::new({
+                    let panicked = Arc::clone(&panicked);
+                    move |_| {
+                        if !panicked.swap(true, Ordering::SeqCst) {
+                            panic!("handler failed");
+                        }
+                        tx.lock().unwrap().send(()).unwrap();
+                    }
+                }),
+            );
+
+            let rocket = rocket::build().manage(
+                Bot::builder(core)
+                    .group(Group::new("token", "confirmation", 1, None))
+                    .dedup(Duration::from_secs(60))
+                    .build(),
+            );
+            let state = State::get(&rocket).unwrap();
+
+            let request = || {
+                Json(CallbackAPIRequest::new(
+                    None,
+                    1,
+                    "message_deny",
+                    Some("a".into()),
+                    Object::new(None, Some(1), None, None, None, None, Default::default()),
+                ))
+            };
+
+            assert_eq!(
+                post(request(), state).await,
+                Err(Status::InternalServerError)
+            );
+            assert_eq!(post(request(), state).await, Ok(VK_OK.to_string()));
+            assert_eq!(rx.try_iter().count(), 1);
+        }
+
+        #[test]
+        #[should_panic(expected = "duplicate group")]
+        fn duplicate_group() {
+            let _ = Bot::builder(Core::new())
+                .group(Group::new("token", "confirmation", 1, None))
+                .group(Group::new("token", "confirmation", 1, None));
+        }
+    }
+
+    mod lifecycle {
+        use super::*;
+        use crate::{core::Event, Handler};
+        use std::{
+            io::{Read, Write},
+            net::{TcpListener, TcpStream},
+            sync::atomic::{AtomicBool, Ordering},
+            thread,
+        };
+
+        fn builder(core: Core) -> BotBuilder {
+            Bot::builder(core)
+                .group(Group::new("token", "confirmation_token", 1, None))
+                .address(Ipv4Addr::LOCALHOST.into())
+                .port(0)
+        }
+
+        fn post_raw(port: u16, body: &str) -> String {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            write!(
+                stream,
+                "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        }
+
+        #[test]
+        fn shutdown_waits_for_handlers() {
+            let done = Arc::new(AtomicBool::new(false));
+
+            let core = Core::new().on(
+                Event::MessageDeny,
+                Handler::new_async({
+                    let done = Arc::clone(&done);
+                    move |_| {
+                        let done = Arc::clone(&done);
+                        async move {
+                            tokio::time::sleep(Duration::from_millis(300)).await;
+                            done.store(true, Ordering::SeqCst);
+                        }
+                    }
+                }),
+            );
+
+            let handle = builder(core).build().start().expect("failed to start");
+            let port = handle.port().unwrap();
+
+            let client = thread::spawn(move || {
+                post_raw(
+                    port,
+                    r#"{"type": "message_deny", "group_id": 1, "object": {"peer_id": 1}}"#,
+                )
+            });
+
+            thread::sleep(Duration::from_millis(100));
+            handle.shutdown().expect("failed to shut down");
+
+            assert!(done.load(Ordering::SeqCst));
+            assert!(client.join().unwrap().ends_with(VK_OK));
+        }
+
+        #[test]
+        fn background_acknowledges_immediately() {
+            let done = Arc::new(AtomicBool::new(false));
+
+            let core = Core::new().on(
+                Event::MessageDeny,
+                Handler::new_async({
+                    let done = Arc::clone(&done);
+                    move |_| {
+                        let done = Arc::clone(&done);
+                        async move {
+                            tokio::time::sleep(Duration::from_millis(300)).await;
+                            done.store(true, Ordering::SeqCst);
+                        }
+                    }
+                }),
+            );
+
+            let handle = builder(core)
+                .background(2, 8, QueueFullPolicy::Wait)
+                .build()
+                .start()
+                .expect("failed to start");
+
+            let response = post_raw(
+                handle.port().unwrap(),
+                r#"{"type": "message_deny", "group_id": 1, "object": {"peer_id": 1}}"#,
+            );
+            assert!(response.ends_with(VK_OK));
+            assert!(!done.load(Ordering::SeqCst));
+
+            // Shutdown waits for queued requests.
+            handle.shutdown().expect("failed to shut down");
+            assert!(done.load(Ordering::SeqCst));
+        }
+
+        #[tokio::test]
+        async fn launch_on_current_runtime() {
+            let core = Core::new().on(
+                Event::MessageDeny,
+                Handler::new_async(|_| tokio::time::sleep(Duration::from_millis(100))),
+            );
+
+            let handle = builder(core)
+                .build()
+                .launch()
+                .await
+                .expect("failed to launch");
+            let port = handle.port().unwrap();
+
+            let response = tokio::task::spawn_blocking(move || {
+                post_raw(
+                    port,
+                    r#"{"type": "message_deny", "group_id": 1, "object": {"peer_id": 1}}"#,
+                )
+            })
+            .await
+            .unwrap();
+            assert!(response.ends_with(VK_OK));
+
+            handle.shutdown().await.expect("failed to shut down");
+        }
+
+        #[test]
+        fn start_reports_bind_errors() {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            let port = listener.local_addr().unwrap().port();
+
+            match builder(Core::new()).port(port).build().start() {
+                Err(BotError::Launch(_)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    mod builder {
+        use super::*;
+        use rocket::{http::ContentType, local::asynchronous::Client};
+
+        async fn client(builder: BotBuilder) -> Client {
+            Client::untracked(
+                builder
+                    .group(Group::new("token", "confirmation_token", 1, None))
+                    .build()
+                    .rocket(),
+            )
+            .await
+            .expect("failed to create client")
+        }
+
+        const CONFIRMATION: &str = r#"{"type": "confirmation", "group_id": 1}"#;
+
+        #[tokio::test]
+        async fn path() {
+            let client = client(Bot::builder(Core::new()).path("/vk/test")).await;
+
+            let res = client
+                .post("/vk/test")
+                .header(ContentType::JSON)
+                .body(CONFIRMATION)
+                .dispatch()
+                .await;
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(res.into_string().await.unwrap(), "confirmation_token");
+
+            let res = client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(CONFIRMATION)
+                .dispatch()
+                .await;
+            assert_eq!(res.status(), Status::NotFound);
+        }
+
+        #[tokio::test]
+        async fn json_limit() {
+            let client = client(Bot::builder(Core::new()).json_limit(16)).await;
+
+            let res = client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(CONFIRMATION)
+                .dispatch()
+                .await;
+            assert_eq!(res.status(), Status::PayloadTooLarge);
+        }
+
+        #[test]
+        fn config() {
+            let bot = Bot::builder(Core::new())
+                .address(Ipv4Addr::LOCALHOST.into())
+                .port(1234)
+                .workers(3)
+                .keep_alive(0)
+                .tls("cert.pem", "key.pem")
+                .build();
+
+            assert_eq!(bot.config.address, IpAddr::from(Ipv4Addr::LOCALHOST));
+            assert_eq!(bot.config.port, 1234);
+            assert_eq!(bot.config.workers, 3);
+            assert_eq!(bot.config.keep_alive, 0);
+            assert!(bot.config.tls.is_some());
+        }
+
+        #[test]
+        #[should_panic(expected = "does not start with")]
+        fn invalid_path() {
+            let _ = Bot::builder(Core::new()).path("vk");
+        }
+    }
 }
@@ -0,0 +1,769 @@
+//! Commands with declared arguments, see [`Core::command`](crate::Core::command).
+//!
+//! # Example
+//! ```
+//! # use vk_bot::{command::Command, Core, Handler};
+//! Core::new().cmd_prefix("/").command(
+//!     Command::new(
+//!         "ban",
+//!         Handler::new(|ctx| {
+//!             let args = ctx.args().unwrap();
+//!             let user: i64 = args.get("user").unwrap();
+//!             let days: u32 = args.get("days").unwrap_or(1);
+//!             let silent = args.flag("silent");
+//!             // ...
+//!         }),
+//!     )
+//!     .arg::<i64>("user")
+//!     .opt_arg::<u32>("days")
+//!     .flag("silent"),
+//! );
+//! ```
+//!
+//! The command above accepts e.g. `/ban 1`, `/ban 1 7 --silent` or
+//! `/ban --silent 1`. Arguments containing spaces can be quoted, e.g.
+//! `/say "hello world"`. When the arguments do not match, the usage message
+//! (`/ban <user> [days] [--silent]`) is sent instead of calling the handler.
+//!
+//! Commands may also have a description and aliases, and be matched
+//! case-insensitively. All commands with a description are listed by the
+//! built-in help command, see [`Command::help`]:
+//!
+//! ```
+//! # use vk_bot::{command::Command, Core, Handler};
+//! Core::new()
+//!     .cmd_prefix("/")
+//!     .command(
+//!         Command::new("ping", Handler::new(|_| {}))
+//!             .description("check whether the bot is alive")
+//!             .alias("p")
+//!             .case_sensitive(false),
+//!     )
+//!     .command(Command::help("help").alias("h").case_sensitive(false));
+//! ```
+
+use crate::core::Handler;
+use regex::{Regex, RegexSet};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Display, Error, Formatter},
+    str::FromStr,
+    sync::Arc,
+};
+
+/// Checks whether a value can be converted to the type of an argument.
+type Validator = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// A declared positional argument.
+#[derive(Clone)]
+struct Arg {
+    name: String,
+    optional: bool,
+    validator: Validator,
+}
+
+impl Debug for Arg {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.debug_struct("Arg")
+            .field("name", &self.name)
+            .field("optional", &self.optional)
+            .finish()
+    }
+}
+
+/// A command with declared arguments and flags.
+#[derive(Debug, Clone)]
+pub struct Command {
+    name: String,
+    handler: Handler,
+    help: bool,
+    description: Option<String>,
+    aliases: Vec<String>,
+    case_sensitive: bool,
+    args: Vec<Arg>,
+    flags: Vec<String>,
+    rest: Option<String>,
+    prefix: Option<String>,
+}
+
+impl Command {
+    /// Creates a new command without arguments, which is handled by
+    /// `handler`.
+    pub fn new(name: &str, handler: Handler) -> Self {
+        Self {
+            name: name.into(),
+            handler,
+            help: false,
+            description: None,
+            aliases: Vec::new(),
+            case_sensitive: true,
+            args: Vec::new(),
+            flags: Vec::new(),
+            rest: None,
+            prefix: None,
+        }
+    }
+
+    /// Creates a new built-in help command, which replies with the list of
+    /// commands set up in the [`Core`](crate::Core) that have a description
+    /// (see [`Command::description`]), including itself.
+    pub fn help(name: &str) -> Self {
+        Self {
+            help: true,
+            ..Command::new(name, Handler::new(|_| {})).description("show this message")
+        }
+    }
+
+    /// Sets the description of this command, shown by the help command.
+    pub fn description(mut self, description: &str) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Adds an alias, which is matched the same way as the name.
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.aliases.push(alias.into());
+        self
+    }
+
+    /// Sets whether the name and the aliases of this command are matched
+    /// case-sensitively (they are by default).
+    pub fn case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_sensitive = case_sensitive;
+        self
+    }
+
+    /// Adds a required positional argument, which must be convertible to `T`.
+    ///
+    /// # Panics
+    /// - if added after an optional argument or [`Command::rest`].
+    pub fn arg<T>(self, name: &str) -> Self
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        if self.args.iter().any(|arg| arg.optional) || self.rest.is_some() {
+            panic!(
+                "attempt to add required argument `{}` after optional ones",
+                name
+            );
+        }
+
+        self.push_arg::<T>(name, false)
+    }
+
+    /// Adds an optional positional argument, which must be convertible to `T`
+    /// if present.
+    ///
+    /// # Panics
+    /// - if added after [`Command::rest`].
+    pub fn opt_arg<T>(self, name: &str) -> Self
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        if self.rest.is_some() {
+            panic!(
+                "attempt to add optional argument `{}` after remaining arguments",
+                name
+            );
+        }
+
+        self.push_arg::<T>(name, true)
+    }
+
+    /// Adds a boolean flag, given as `--name`.
+    pub fn flag(mut self, name: &str) -> Self {
+        self.flags.push(name.into());
+        self
+    }
+
+    /// Allows any number of remaining positional arguments (see
+    /// [`Args::rest`]). `name` is only used in the usage message.
+    ///
+    /// If the command declares no flags, arguments starting with `--` and
+    /// unclosed quotes are accepted as positional arguments as given.
+    pub fn rest(mut self, name: &str) -> Self {
+        self.rest = Some(name.into());
+        self
+    }
+
+    /// Returns the name of this command.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the aliases of this command.
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    /// Returns the handler of this command.
+    pub fn handler(&self) -> &Handler {
+        &self.handler
+    }
+
+    /// Returns whether this is the built-in help command.
+    pub(crate) fn is_help(&self) -> bool {
+        self.help
+    }
+
+    /// Returns the handler of this command for modification.
+    pub(crate) fn handler_mut(&mut self) -> &mut Handler {
+        &mut self.handler
+    }
+
+    /// Fixes the prefix of this command when its [`Core`](crate::Core) is
+    /// mounted (see [`Core::mount`](crate::Core::mount)): the prefix of the
+    /// mounted `Core`, preceded by the prefix of the mount, if any. Without
+    /// either, the command uses the prefix of the `Core` it is mounted into.
+    pub(crate) fn mount(&mut self, mount: Option<&str>, prefix: Option<&str>) {
+        let prefix = self.prefix.take().or_else(|| prefix.map(Into::into));
+        self.prefix = match mount {
+            Some(mount) => Some(format!("{} {}", mount, prefix.unwrap_or_default())),
+            None => prefix,
+        };
+    }
+
+    /// Returns whether this command and `other` have the same prefix and a
+    /// name or an alias in common.
+    pub(crate) fn conflicts_with(&self, other: &Command) -> Option<&str> {
+        if self.prefix != other.prefix {
+            return None;
+        }
+
+        let case_sensitive = self.case_sensitive && other.case_sensitive;
+
+        self.names().find(|name| {
+            other.names().any(|other| {
+                if case_sensitive {
+                    name == &other
+                } else {
+                    name.to_lowercase() == other.to_lowercase()
+                }
+            })
+        })
+    }
+
+    /// Returns the regex matching the name or any alias of this command,
+    /// followed by a word boundary (or whitespace / end of text for names not
+    /// ending with a word character).
+    fn names_regex(&self) -> String {
+        let mut names: Vec<_> = self.names().collect();
+        // Prefer the longest name when one is a prefix of another.
+        names.sort_by_key(|name| Reverse(name.len()));
+
+        let names: Vec<_> = names
+            .into_iter()
+            .map(|name| {
+                let boundary = match name.chars().last() {
+                    Some(c) if c.is_alphanumeric() || c == '_' => r"\b",
+                    _ => r"(?:\s|$)",
+                };
+                format!("{}{}", regex::escape(name), boundary)
+            })
+            .collect();
+
+        format!(
+            "(?{}:{})",
+            if self.case_sensitive { "-i" } else { "i" },
+            names.join("|")
+        )
+    }
+
+    /// Returns the line describing this command in the help message, or
+    /// `None` if it has no description.
+    pub(crate) fn help_line(&self, prefix: &str) -> Option<String> {
+        let description = self.description.as_ref()?;
+        let prefix = self.prefix.as_deref().unwrap_or(prefix);
+        let mut line = format!("{} — {}", self.usage(prefix), description);
+
+        if !self.aliases.is_empty() {
+            let aliases: Vec<_> = self
+                .aliases
+                .iter()
+                .map(|alias| format!("{}{}", prefix, alias))
+                .collect();
+            line += &format!(" (aliases: {})", aliases.join(", "));
+        }
+
+        Some(line)
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.name.as_str()).chain(self.aliases.iter().map(String::as_str))
+    }
+
+    /// Returns the usage message of this command, e.g.
+    /// `/ban <user> [days] [--silent]`.
+    ///
+    /// `prefix` is the command prefix of the [`Core`](crate::Core); commands
+    /// of a mounted `Core` use their own prefix instead.
+    pub fn usage(&self, prefix: &str) -> String {
+        let prefix = self.prefix.as_deref().unwrap_or(prefix);
+        let mut usage = format!("{}{}", prefix, self.name);
+
+        for arg in &self.args {
+            if arg.optional {
+                usage += &format!(" [{}]", arg.name);
+            } else {
+                usage += &format!(" <{}>", arg.name);
+            }
+        }
+
+        for flag in &self.flags {
+            usage += &format!(" [--{}]", flag);
+        }
+
+        if let Some(rest) = &self.rest {
+            usage += &format!(" [{}...]", rest);
+        }
+
+        usage
+    }
+
+    /// Parses the arguments of this command (the text after the command
+    /// itself).
+    pub fn parse(&self, text: &str) -> Result<Args, ArgsError> {
+        let lenient = self.rest.is_some() && self.flags.is_empty();
+        let mut positional = Vec::new();
+        let mut flags = HashSet::new();
+
+        for token in tokenize(text, lenient)? {
+            match token.strip_prefix("--") {
+                Some(flag) if !token.quoted && !lenient => {
+                    if !self.flags.iter().any(|f| f == flag) {
+                        return Err(ArgsError::UnknownFlag(flag.into()));
+                    }
+                    flags.insert(flag.to_string());
+                }
+                _ => positional.push(token.value),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let mut values = HashMap::new();
+
+        for arg in &self.args {
+            match positional.next() {
+                Some(value) => {
+                    (arg.validator)(&value).map_err(|error| ArgsError::Invalid {
+                        name: arg.name.clone(),
+                        value: value.clone(),
+                        error,
+                    })?;
+                    values.insert(arg.name.clone(), value);
+                }
+                None if arg.optional => break,
+                None => return Err(ArgsError::Missing(arg.name.clone())),
+            }
+        }
+
+        let rest: Vec<_> = positional.collect();
+        if let (None, Some(value)) = (&self.rest, rest.first()) {
+            return Err(ArgsError::Unexpected(value.clone()));
+        }
+
+        Ok(Args {
+            values,
+            flags,
+            rest,
+        })
+    }
+
+    fn push_arg<T>(mut self, name: &str, optional: bool) -> Self
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.args.push(Arg {
+            name: name.into(),
+            optional,
+            validator: Arc::new(|value| value.parse::<T>().map(|_| ()).map_err(|e| e.to_string())),
+        });
+        self
+    }
+}
+
+/// Commands compiled for matching, built once per [`Core`](crate::Core).
+#[derive(Debug, Clone)]
+pub(crate) struct CommandTable {
+    mention: Regex,
+    set: RegexSet,
+    regexes: Vec<Regex>,
+}
+
+impl CommandTable {
+    /// Compiles the given commands using the given command prefix (unless a
+    /// command has its own one). Spaces in the prefix match any whitespace.
+    pub(crate) fn new(commands: &[Command], prefix: &str) -> Self {
+        let patterns: Vec<_> = commands
+            .iter()
+            .map(|command| {
+                let prefix: Vec<_> = command
+                    .prefix
+                    .as_deref()
+                    .unwrap_or(prefix)
+                    .split(' ')
+                    .map(regex::escape)
+                    .collect();
+                format!(r"^\s*{}{}", prefix.join(r"\s+"), command.names_regex())
+            })
+            .collect();
+
+        Self {
+            mention: Regex::new(r"^\s*\[club(\d+)\|[^\]]*\]").expect("invalid regex"),
+            set: RegexSet::new(&patterns).expect("invalid regex"),
+            regexes: patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern).expect("invalid regex"))
+                .collect(),
+        }
+    }
+
+    /// Finds the commands `text` starts with (optionally after a mention of
+    /// the community), and returns their indices and the ends of the matches,
+    /// best match first.
+    ///
+    /// The longest match is the best one, and then the command set up first.
+    pub(crate) fn find(&self, text: &str, group_id: i32) -> Vec<(usize, usize)> {
+        let start = match self.mention.captures(text) {
+            Some(captures) => match captures[1].parse::<i64>() {
+                Ok(id) if id == i64::from(group_id) => captures[0].len(),
+                _ => return Vec::new(),
+            },
+            None => 0,
+        };
+        let text = &text[start..];
+
+        let mut found: Vec<_> = self
+            .set
+            .matches(text)
+            .into_iter()
+            .filter_map(|i| Some((i, start + self.regexes[i].find(text)?.end())))
+            .collect();
+        found.sort_unstable_by_key(|&(i, end)| (Reverse(end), i));
+        found
+    }
+}
+
+/// Arguments of a command, see [`Context::args`](crate::Context::args).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Args {
+    values: HashMap<String, String>,
+    flags: HashSet<String>,
+    rest: Vec<String>,
+}
+
+impl Args {
+    /// Returns the value of a positional argument converted to `T`, or `None`
+    /// if it is absent or cannot be converted.
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.raw(name)?.parse().ok()
+    }
+
+    /// Returns the value of a positional argument as given.
+    pub fn raw(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Returns whether a flag is set.
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains(name)
+    }
+
+    /// Returns the remaining positional arguments (see [`Command::rest`]).
+    pub fn rest(&self) -> &[String] {
+        &self.rest
+    }
+}
+
+/// Error type for [`Command::parse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgsError {
+    /// A quoted argument is not closed.
+    UnclosedQuote,
+    /// A required argument is missing.
+    Missing(String),
+    /// An argument could not be converted to its type.
+    Invalid {
+        /// Name of the argument.
+        name: String,
+        /// The given value.
+        value: String,
+        /// The conversion error.
+        error: String,
+    },
+    /// There are more positional arguments than declared.
+    Unexpected(String),
+    /// A flag is not declared.
+    UnknownFlag(String),
+}
+
+impl Display for ArgsError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        match self {
+            ArgsError::UnclosedQuote => f.write_str("unclosed quote"),
+            ArgsError::Missing(name) => write!(f, "missing argument `{}`", name),
+            ArgsError::Invalid { name, value, error } => {
+                write!(f, "invalid argument `{}` ({:?}): {}", name, value, error)
+            }
+            ArgsError::Unexpected(value) => write!(f, "unexpected argument {:?}", value),
+            ArgsError::UnknownFlag(name) => write!(f, "unknown flag `--{}`", name),
+        }
+    }
+}
+
+impl std::error::Error for ArgsError {}
+
+/// A token of command arguments.
+struct Token {
+    value: String,
+    quoted: bool,
+}
+
+impl std::ops::Deref for Token {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+/// Splits arguments by whitespace, keeping quoted (using `"` or `'`) strings
+/// together. Inside quotes, `\` escapes the next character.
+///
+/// If `lenient`, an unclosed quote is kept as a part of an unquoted token
+/// instead of being an error.
+fn tokenize(text: &str, lenient: bool) -> Result<Vec<Token>, ArgsError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let first = match chars.next() {
+            Some(c) => c,
+            None => return Ok(tokens),
+        };
+
+        if first == '"' || first == '\'' {
+            let unquoted = chars.clone();
+
+            match quoted(&mut chars, first) {
+                Some(value) => {
+                    tokens.push(Token {
+                        value,
+                        quoted: true,
+                    });
+                    continue;
+                }
+                None if lenient => chars = unquoted,
+                None => return Err(ArgsError::UnclosedQuote),
+            }
+        }
+
+        let mut value = String::new();
+        value.push(first);
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            value.push(c);
+        }
+
+        tokens.push(Token {
+            value,
+            quoted: false,
+        });
+    }
+}
+
+/// Returns the rest of a string quoted using `quote`, or `None` if it is not
+/// closed.
+fn quoted(chars: &mut impl Iterator<Item = char>, quote: char) -> Option<String> {
+    let mut value = String::new();
+
+    loop {
+        match chars.next()? {
+            c if c == quote => return Some(value),
+            '\\' => value.push(chars.next()?),
+            c => value.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban() -> Command {
+        Command::new("ban", Handler::new(|_| {}))
+            .arg::<i64>("user")
+            .opt_arg::<u32>("days")
+            .flag("silent")
+    }
+
+    #[test]
+    fn positional_and_flags() {
+        let args = ban().parse(" 1  7 --silent").unwrap();
+        assert_eq!(args.get::<i64>("user"), Some(1));
+        assert_eq!(args.get::<u32>("days"), Some(7));
+        assert!(args.flag("silent"));
+
+        let args = ban().parse("--silent 1").unwrap();
+        assert_eq!(args.get::<i64>("user"), Some(1));
+        assert_eq!(args.get::<u32>("days"), None);
+        assert!(args.flag("silent"));
+
+        assert!(!ban().parse("1").unwrap().flag("silent"));
+    }
+
+    #[test]
+    fn quoted() {
+        let command = Command::new("say", Handler::new(|_| {}))
+            .arg::<String>("text")
+            .flag("loud")
+            .rest("more");
+
+        let args = command
+            .parse(r#""hello world" 'it\'s' "--not-a-flag""#)
+            .unwrap();
+        assert_eq!(args.raw("text"), Some("hello world"));
+        assert_eq!(args.rest(), ["it's", "--not-a-flag"]);
+
+        assert_eq!(command.parse(r#""hello"#), Err(ArgsError::UnclosedQuote));
+    }
+
+    #[test]
+    fn lenient() {
+        let command = Command::new("say", Handler::new(|_| {})).rest("text");
+
+        let args = command.parse(r#"--loud 'hi there' "it's "#).unwrap();
+        assert_eq!(args.rest(), ["--loud", "hi there", "\"it's"]);
+
+        let args = command.parse(r#"a 'b c"#).unwrap();
+        assert_eq!(args.rest(), ["a", "'b", "c"]);
+
+        assert_eq!(
+            command.flag("loud").parse("--quiet"),
+            Err(ArgsError::UnknownFlag("quiet".into()))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(ban().parse(""), Err(ArgsError::Missing("user".into())));
+        assert_eq!(ban().parse("1 2 3"), Err(ArgsError::Unexpected("3".into())));
+        assert_eq!(
+            ban().parse("1 --loud"),
+            Err(ArgsError::UnknownFlag("loud".into()))
+        );
+        match ban().parse("abc") {
+            Err(ArgsError::Invalid { name, value, .. }) => {
+                assert_eq!((name.as_str(), value.as_str()), ("user", "abc"))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn usage() {
+        assert_eq!(ban().usage("/"), "/ban <user> [days] [--silent]");
+        assert_eq!(
+            Command::new("echo", Handler::new(|_| {}))
+                .rest("text")
+                .usage(""),
+            "echo [text...]"
+        );
+    }
+
+    mod table {
+        use super::*;
+
+        fn command(name: &str) -> Command {
+            Command::new(name, Handler::new(|_| {}))
+        }
+
+        fn find(commands: &[Command], prefix: &str, text: &str) -> Option<(usize, usize)> {
+            CommandTable::new(commands, prefix)
+                .find(text, 1)
+                .first()
+                .copied()
+        }
+
+        #[test]
+        fn longest_match() {
+            let commands = [command("test"), command("testing"), command("test all")];
+
+            assert_eq!(find(&commands, "/", "/test 1"), Some((0, 5)));
+            assert_eq!(find(&commands, "/", "/testing 1"), Some((1, 8)));
+            assert_eq!(find(&commands, "/", "/test all 1"), Some((2, 9)));
+            assert_eq!(find(&commands, "/", "/test allowed"), Some((0, 5)));
+            assert_eq!(
+                CommandTable::new(&commands, "/").find("/test all", 1),
+                [(2, 9), (0, 5)]
+            );
+        }
+
+        #[test]
+        fn word_boundary() {
+            let commands = [command("test"), command("?")];
+
+            assert_eq!(find(&commands, "/", "/tester"), None);
+            assert_eq!(find(&commands, "/", "/test, please"), Some((0, 5)));
+            assert_eq!(find(&commands, "/", "/?"), Some((1, 2)));
+            assert_eq!(find(&commands, "/", "/?!"), None);
+        }
+
+        #[test]
+        fn mention() {
+            let commands = [command("test")];
+
+            assert_eq!(find(&commands, "/", " [club1|@bot] /test"), Some((0, 19)));
+            assert_eq!(find(&commands, "/", "[club2|@other] /test"), None);
+            assert_eq!(find(&commands, "/", "x /test"), None);
+        }
+
+        #[test]
+        fn same_length() {
+            let commands = [command("a").case_sensitive(false), command("A").alias("b")];
+
+            assert_eq!(find(&commands, "", "A"), Some((0, 1)));
+            assert_eq!(find(&commands, "", "b"), Some((1, 1)));
+        }
+    }
+
+    #[test]
+    fn help_line() {
+        assert_eq!(ban().help_line("/"), None);
+        assert_eq!(
+            ban()
+                .description("ban a user")
+                .alias("b")
+                .alias("бан")
+                .help_line("/")
+                .as_deref(),
+            Some("/ban <user> [days] [--silent] — ban a user (aliases: /b, /бан)")
+        );
+    }
+
+    #[test]
+    fn conflicts() {
+        let help = Command::help("help").alias("h");
+        assert_eq!(help.conflicts_with(&Command::help("H")), None);
+        assert_eq!(
+            help.conflicts_with(&Command::help("H").case_sensitive(false)),
+            Some("h")
+        );
+        assert_eq!(
+            help.conflicts_with(&Command::new("x", Handler::new(|_| {})).alias("help")),
+            Some("help")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to add required argument `b` after optional ones")]
+    fn required_after_optional() {
+        Command::new("c", Handler::new(|_| {}))
+            .opt_arg::<String>("a")
+            .arg::<String>("b");
+    }
+}
@@ -1,52 +1,182 @@
 //! The [`Context`] struct.
 
 use crate::{
-    core::Event,
+    api,
+    command::Args,
+    core::{Event, ParsedPayload},
+    objects::{ClientInfo, Message, ServiceAction},
     request::{CallbackAPIRequest, Object},
-    response::Response,
+    response::{EventAnswer, Response},
+    session::{SessionMut, Sessions},
+    state::States,
 };
+use regex::Regex;
 use rvk::{error::Error, methods::messages, objects::Integer, APIClient, Params};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Display, Formatter},
+    sync::{Arc, Mutex},
+};
+
+/// Error type for [`Context::new`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContextError {
+    /// A field required for the event is missing on the object.
+    MissingField {
+        /// The event being handled.
+        event: Event,
+        /// Name of the missing field.
+        field: &'static str,
+    },
+}
+
+impl Display for ContextError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            ContextError::MissingField { event, field } => {
+                write!(f, "no {} on `{}` object", field, event)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ContextError {}
+
+/// Groups captured by the regex of a handler, see [`Context::captures`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Captures {
+    groups: Vec<Option<String>>,
+    names: HashMap<String, usize>,
+}
+
+impl Captures {
+    /// Creates a new [`Captures`] from the groups captured by `re`.
+    pub fn new(re: &Regex, captures: &regex::Captures) -> Self {
+        Self {
+            groups: captures
+                .iter()
+                .map(|group| group.map(|m| m.as_str().into()))
+                .collect(),
+            names: re
+                .capture_names()
+                .enumerate()
+                .filter_map(|(i, name)| Some((name?.into(), i)))
+                .collect(),
+        }
+    }
+
+    /// Returns the group with the given index, where `0` is the whole match.
+    pub fn get(&self, i: usize) -> Option<&str> {
+        self.groups.get(i)?.as_deref()
+    }
+
+    /// Returns the group with the given name.
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.get(*self.names.get(name)?)
+    }
+
+    /// Returns the number of groups, including the whole match.
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Returns whether there are no groups.
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+/// The payload deserialized for a
+/// [`Core::typed_payload`](crate::Core::typed_payload) handler. It is shared
+/// between the clones of a [`Context`], so that the handler can take it from
+/// its own clone.
+#[derive(Clone, Default)]
+struct SharedPayload(Arc<Mutex<Option<ParsedPayload>>>);
+
+impl Debug for SharedPayload {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str("SharedPayload {...}")
+    }
+}
 
 /// Stores information necessary for handlers, allows to send the resulting
 /// message.
-#[derive(Debug)]
-pub struct Context<'api> {
+#[derive(Debug, Clone)]
+pub struct Context {
     group_id: i32,
     event: Event,
+    event_type: String,
     object: Object,
-    api: &'api APIClient,
-    peer_id: Integer,
+    message: Option<Message>,
+    args: Option<Args>,
+    captures: Option<Captures>,
+    parsed_payload: SharedPayload,
+    api: Arc<APIClient>,
+    peer_id: Option<Integer>,
     response: Response,
+    states: States,
+    sessions: Sessions,
 }
 
-impl<'api> Context<'api> {
+impl Context {
     /// Creates a new [`Context`].
     ///
-    /// # Panics
-    /// - no user_id on object (only [`Event::MessageAllow`])
+    /// # Errors
+    /// - no user_id on object ([`Event::MessageAllow`])
     /// - no from_id on object ([`Event::MessageTypingState`])
-    /// - no peer_id on object (other events)
-    pub fn new(event: Event, req: &CallbackAPIRequest, api: &'api APIClient) -> Self {
+    /// - no peer_id on object ([`Event::MessageNew`], [`Event::MessageReply`],
+    ///   [`Event::MessageEdit`])
+    ///
+    /// For [`Event::MessageDeny`] and [`Event::MessageEvent`], peer_id or
+    /// else user_id on object is used. For other events, peer_id on object is
+    /// used; if there is none (e.g. for community events), the context has no
+    /// peer.
+    pub fn new(
+        event: Event,
+        req: &CallbackAPIRequest,
+        api: Arc<APIClient>,
+    ) -> Result<Self, ContextError> {
         let object = req.object();
+        let required = |id: Option<Integer>, field| {
+            id.map(Some)
+                .ok_or(ContextError::MissingField { event, field })
+        };
 
         let peer_id = match event {
-            Event::MessageAllow => object
-                .user_id()
-                .expect("no user_id on message_allow object"),
-            Event::MessageTypingState => object
-                .get_from_id()
-                .expect("no from_id on message_typing_state object"),
-            _ => object.peer_id().expect("no peer_id on object"),
+            Event::MessageAllow => required(*object.user_id(), "user_id")?,
+            Event::MessageTypingState => required(*object.get_from_id(), "from_id")?,
+            Event::MessageNew | Event::MessageReply | Event::MessageEdit => {
+                required(*object.peer_id(), "peer_id")?
+            }
+            Event::MessageDeny | Event::MessageEvent => object.peer_id().or(*object.user_id()),
+            _ => *object.peer_id(),
         };
 
-        Self {
+        let message = match event {
+            Event::MessageNew | Event::MessageReply | Event::MessageEdit => object
+                .parse()
+                .map_err(|e| warn!("failed to parse `{}` object: {}", event, e))
+                .ok(),
+            _ => None,
+        };
+
+        Ok(Self {
             group_id: req.group_id(),
             event,
+            event_type: req.r#type().into(),
             object: object.clone(),
+            message,
+            args: None,
+            captures: None,
+            parsed_payload: Default::default(),
             api,
             peer_id,
             response: Response::new(),
-        }
+            states: Default::default(),
+            sessions: Default::default(),
+        })
     }
 
     /// Returns the group ID.
@@ -60,13 +190,159 @@ impl<'api> Context<'api> {
         self.event
     }
 
+    /// Returns the Callback API event type as received, e.g. for an
+    /// [`Event::Unknown`].
+    pub fn event_type(&self) -> &str {
+        &self.event_type
+    }
+
+    /// Returns the message associated with the event, for
+    /// [`Event::MessageNew`], [`Event::MessageReply`] and
+    /// [`Event::MessageEdit`].
+    ///
+    /// Returns `None` for other events, or if the object could not be parsed
+    /// (which is logged).
+    pub fn message(&self) -> Option<&Message> {
+        self.message.as_ref()
+    }
+
+    /// Returns the service action of the message associated with the event, if
+    /// any. See [`Context::message`].
+    pub fn action(&self) -> Option<&ServiceAction> {
+        self.message.as_ref()?.action()
+    }
+
+    /// Returns information about the features supported by the user's client,
+    /// if sent with the event (see [`Object::client_info`]).
+    pub fn client_info(&self) -> Option<&ClientInfo> {
+        self.object.client_info()
+    }
+
+    /// Returns the arguments of the command being handled, if the handler was
+    /// set up via [`Core::cmd`](crate::Core::cmd) or
+    /// [`Core::command`](crate::Core::command).
+    pub fn args(&self) -> Option<&Args> {
+        self.args.as_ref()
+    }
+
+    /// Sets the arguments of the command being handled.
+    pub(crate) fn set_args(&mut self, args: Args) {
+        self.args = Some(args);
+    }
+
+    /// Returns the groups captured by the regex of the handler, if it was set
+    /// up via [`Core::regex`](crate::Core::regex).
+    pub fn captures(&self) -> Option<&Captures> {
+        self.captures.as_ref()
+    }
+
+    /// Returns the group with the given name captured by the regex of the
+    /// handler. See [`Context::captures`].
+    pub fn capture(&self, name: &str) -> Option<&str> {
+        self.captures.as_ref()?.name(name)
+    }
+
+    /// Sets the groups captured by the regex of the handler.
+    pub(crate) fn set_captures(&mut self, captures: Captures) {
+        self.captures = Some(captures);
+    }
+
+    /// Sets the payload deserialized for a
+    /// [`Core::typed_payload`](crate::Core::typed_payload) handler.
+    pub(crate) fn set_parsed_payload(&mut self, payload: ParsedPayload) {
+        *self.parsed_payload.0.lock().unwrap() = Some(payload);
+    }
+
+    /// Takes the payload set by [`Context::set_parsed_payload`].
+    pub(crate) fn take_parsed_payload(&self) -> Option<ParsedPayload> {
+        self.parsed_payload.0.lock().unwrap().take()
+    }
+
+    /// Returns the ID of the conversation the response is sent to, if the event
+    /// has one.
+    pub fn peer_id(&self) -> Option<Integer> {
+        self.peer_id
+    }
+
+    /// Returns the state of the conversation (see
+    /// [`Core::state`](crate::Core::state)), if any.
+    ///
+    /// By default, every user in a group chat has a state of their own (see
+    /// [`Core::state_scope`](crate::Core::state_scope)).
+    pub fn state(&self) -> Option<String> {
+        self.states.get(self)
+    }
+
+    /// Sets the state of the conversation, so that its next messages are
+    /// routed to the handlers of the state first (see
+    /// [`Core::state`](crate::Core::state)).
+    ///
+    /// If the state has a timeout, its handler is called with a copy of this
+    /// context (with an empty response) once the state expires.
+    ///
+    /// Does nothing if the context has no user or peer required by the scope
+    /// of states (see [`Core::state_scope`](crate::Core::state_scope)).
+    pub fn set_state(&self, name: &str) {
+        self.states.set(self, name);
+    }
+
+    /// Clears the state of the conversation.
+    pub fn clear_state(&self) {
+        self.states.clear(self);
+    }
+
+    /// Sets the states of conversations shared with the [`Core`](crate::Core).
+    pub(crate) fn set_states(&mut self, states: States) {
+        self.states = states;
+    }
+
+    /// Returns the session of the event (see
+    /// [`Core::sessions`](crate::Core::sessions)), or `None` if there is none
+    /// or it cannot be deserialized into `T`.
+    pub fn session<T: DeserializeOwned>(&self) -> Option<T> {
+        self.sessions.load(self)
+    }
+
+    /// Returns the session of the event for modification, or a default one.
+    /// The session is saved once the returned [`SessionMut`] is dropped, if
+    /// it was changed.
+    ///
+    /// The session is not saved if the event has no user or conversation
+    /// required by the [`SessionScope`](crate::session::SessionScope).
+    ///
+    /// Sessions are not locked: if events sharing a session are handled
+    /// concurrently, the session saved last wins (see
+    /// [`BotBuilder::background`](crate::bot::BotBuilder::background) for
+    /// handling events of a conversation in order).
+    pub fn session_mut<T>(&self) -> SessionMut<T>
+    where
+        T: Serialize + DeserializeOwned + Default,
+    {
+        self.sessions.load_mut(self)
+    }
+
+    /// Removes the session of the event.
+    pub fn clear_session(&self) {
+        self.sessions.remove(self);
+    }
+
+    /// Sets the sessions shared with the [`Core`](crate::Core).
+    pub(crate) fn set_sessions(&mut self, sessions: Sessions) {
+        self.sessions = sessions;
+    }
+
+    /// Returns the ID of the user who caused the event, if any.
+    pub(crate) fn sender_id(&self) -> Option<Integer> {
+        self.object.get_from_id().or(*self.object.user_id())
+    }
+
     /// Returns the object associated with the event (given by Callback API).
     pub fn object(&self) -> &Object {
         &self.object
     }
 
     /// Returns the global [`rvk::APIClient`] which is used in this bot.
-    pub fn api(&self) -> &APIClient {
+    pub fn api(&self) -> &Arc<APIClient> {
         &self.api
     }
 
@@ -79,13 +355,94 @@ impl<'api> Context<'api> {
     ///
     /// This does not erase the response object. You can send multiple messages.
     ///
-    /// This method currently blocks until the [`rvk::APIClient`] is available,
-    /// so only one message is being sent at a given time. This behavior may
-    /// change.
-    pub fn send(&self) -> Result<(), Error> {
+    /// Fails without making a request if the context has no peer.
+    ///
+    /// The API request itself is blocking, so it is made on a separate thread
+    /// to avoid blocking the async runtime.
+    pub async fn send(&self) -> Result<(), Error> {
+        let params = self.send_params()?;
+        api::call_method(&self.api, "messages.send", params)
+            .await
+            .map(|_| ())
+    }
+
+    /// Sends the response, blocking the current thread until it is sent.
+    ///
+    /// This does not erase the response object. You can send multiple messages.
+    ///
+    /// Blocks a thread of the async runtime when called from a handler, so
+    /// use [`Context::send`] there; this is meant for code running outside of
+    /// the runtime.
+    pub fn send_blocking(&self) -> Result<(), Error> {
+        messages::send(&self.api, self.send_params()?).map(|_| ())
+    }
+
+    /// Answers the [`Event::MessageEvent`] being handled, stopping the loading
+    /// animation on the pressed callback button, and optionally performing an
+    /// action (see [`EventAnswer`]).
+    ///
+    /// Fails without making a request if the event being handled is not an
+    /// [`Event::MessageEvent`].
+    pub async fn answer_event(&self, answer: Option<EventAnswer>) -> Result<(), Error> {
+        let params = self.answer_event_params(answer)?;
+        api::call_method(&self.api, "messages.sendMessageEventAnswer", params)
+            .await
+            .map(|_| ())
+    }
+
+    /// Answers the [`Event::MessageEvent`] being handled, blocking the current
+    /// thread until the answer is sent. Like [`Context::send_blocking`], this
+    /// is meant for code running outside of the async runtime; see
+    /// [`Context::answer_event`].
+    pub fn answer_event_blocking(&self, answer: Option<EventAnswer>) -> Result<(), Error> {
+        let params = self.answer_event_params(answer)?;
+        self.api
+            .call_method("messages.sendMessageEventAnswer", params)
+            .map(|_| ())
+    }
+
+    /// Returns parameters for the `messages.sendMessageEventAnswer` method.
+    fn answer_event_params(&self, answer: Option<EventAnswer>) -> Result<Params, Error> {
+        let event_id = match (self.event, self.object.extra().get("event_id")) {
+            (Event::MessageEvent, Some(Value::String(event_id))) => event_id,
+            _ => {
+                return Err(Error::Other(
+                    "not a `message_event` with an event_id".into(),
+                ))
+            }
+        };
+        let (user_id, peer_id) = match (self.object.user_id(), self.peer_id) {
+            (Some(user_id), Some(peer_id)) => (user_id, peer_id),
+            _ => {
+                return Err(Error::Other(
+                    "no user_id or peer_id to answer the event".into(),
+                ))
+            }
+        };
+
+        let mut params = Params::new();
+        params.insert("event_id".into(), event_id.clone());
+        params.insert("user_id".into(), user_id.to_string());
+        params.insert("peer_id".into(), peer_id.to_string());
+
+        if let Some(answer) = answer {
+            params.insert("event_data".into(), serde_json::to_string(&answer)?);
+        }
+
+        trace!("answering event {:#?}", params);
+
+        Ok(params)
+    }
+
+    /// Returns parameters for the `messages.send` method.
+    fn send_params(&self) -> Result<Params, Error> {
+        let peer_id = self
+            .peer_id
+            .ok_or_else(|| Error::Other("no peer to send the response to".into()))?;
+
         let mut params = Params::new();
 
-        params.insert("peer_id".into(), format!("{}", self.peer_id));
+        params.insert("peer_id".into(), format!("{}", peer_id));
 
         let res = &self.response;
         let msg = res.message();
@@ -118,6 +475,6 @@ impl<'api> Context<'api> {
 
         trace!("sending message {:#?}", params);
 
-        messages::send(self.api, params).map(|_| ())
+        Ok(params)
     }
 }
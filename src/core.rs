@@ -1,18 +1,32 @@
 //! The [`Core`] struct, supported [`Event`]s, and
 //! handler / tester types.
 
-use crate::{context::Context, request::CallbackAPIRequest};
-use regex::Regex;
+use crate::{
+    command::{Command, CommandTable},
+    context::{Captures, Context, ContextError},
+    guard::Guard,
+    objects::ActionKind,
+    request::CallbackAPIRequest,
+    session::{SessionScope, SessionStore, Sessions},
+    state::States,
+};
+use regex::{Regex, RegexSet};
 use rvk::APIClient;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use std::{
+    any::Any,
     collections::{hash_map::Entry, HashMap},
     convert::TryFrom,
     fmt::{Debug, Display, Error, Formatter},
+    future::{self, Future},
     ops::Deref,
+    pin::Pin,
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, OnceLock},
+    time::Duration,
 };
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 /// Events that are supported for event handlers. See also [`Core::on`].
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -29,6 +43,36 @@ pub enum Event {
     MessageAllow,
     /// Callback API: `message_deny`.
     MessageDeny,
+    /// Callback API: `message_event`, generated when a callback button (see
+    /// [`Action::Callback`](crate::keyboard::Action::Callback)) is pressed.
+    MessageEvent,
+
+    /// Callback API: `wall_post_new`, see [`WallPost`](crate::objects::WallPost).
+    WallPostNew,
+    /// Callback API: `wall_reply_new`, see
+    /// [`WallReply`](crate::objects::WallReply).
+    WallReplyNew,
+    /// Callback API: `group_join`, see [`GroupJoin`](crate::objects::GroupJoin).
+    GroupJoin,
+    /// Callback API: `group_leave`, see
+    /// [`GroupLeave`](crate::objects::GroupLeave).
+    GroupLeave,
+    /// Callback API: `photo_new`, see [`Photo`](crate::objects::Photo).
+    PhotoNew,
+    /// Callback API: `like_add`, see [`Like`](crate::objects::Like).
+    LikeAdd,
+    /// Callback API: `like_remove`, see [`Like`](crate::objects::Like).
+    LikeRemove,
+    /// Callback API: `user_block`, see [`UserBlock`](crate::objects::UserBlock).
+    UserBlock,
+    /// Callback API: `user_unblock`, see
+    /// [`UserUnblock`](crate::objects::UserUnblock).
+    UserUnblock,
+    /// Callback API: `poll_vote_new`, see [`PollVote`](crate::objects::PollVote).
+    PollVoteNew,
+    /// Callback API: `group_officers_edit`, see
+    /// [`GroupOfficersEdit`](crate::objects::GroupOfficersEdit).
+    GroupOfficersEdit,
 
     /// Generated instead of [`Event::MessageNew`] when start button was
     /// pressed.
@@ -40,7 +84,9 @@ pub enum Event {
     /// Generated when no matching handler for an event / payload / command /
     /// regex is found.
     NoMatch,
-    // TODO: HandlerError event?
+    /// Generated for Callback API events of types not listed here. The type
+    /// is available via [`Context::event_type`].
+    Unknown,
 }
 
 impl Display for Event {
@@ -52,15 +98,49 @@ impl Display for Event {
             Event::MessageTypingState => "message_typing_state",
             Event::MessageAllow => "message_allow",
             Event::MessageDeny => "message_deny",
+            Event::MessageEvent => "message_event",
+
+            Event::WallPostNew => "wall_post_new",
+            Event::WallReplyNew => "wall_reply_new",
+            Event::GroupJoin => "group_join",
+            Event::GroupLeave => "group_leave",
+            Event::PhotoNew => "photo_new",
+            Event::LikeAdd => "like_add",
+            Event::LikeRemove => "like_remove",
+            Event::UserBlock => "user_block",
+            Event::UserUnblock => "user_unblock",
+            Event::PollVoteNew => "poll_vote_new",
+            Event::GroupOfficersEdit => "group_officers_edit",
 
             Event::Start => "start",
             Event::ServiceAction => "service_action",
 
             Event::NoMatch => "no_match",
+            Event::Unknown => "unknown",
         })
     }
 }
 
+impl Event {
+    /// Returns whether this event is related to a conversation with the
+    /// community.
+    fn is_message(self) -> bool {
+        matches!(
+            self,
+            Event::MessageNew
+                | Event::MessageReply
+                | Event::MessageEdit
+                | Event::MessageTypingState
+                | Event::MessageAllow
+                | Event::MessageDeny
+                | Event::MessageEvent
+                | Event::Start
+                | Event::ServiceAction
+                | Event::NoMatch
+        )
+    }
+}
+
 /// Error type for `impl FromStr for Event`.
 #[derive(Debug, Clone, PartialOrd, PartialEq, Eq, Ord)]
 pub struct EventFromStrError(String);
@@ -82,11 +162,25 @@ impl FromStr for Event {
             "message_typing_state" => Ok(Event::MessageTypingState),
             "message_allow" => Ok(Event::MessageAllow),
             "message_deny" => Ok(Event::MessageDeny),
+            "message_event" => Ok(Event::MessageEvent),
+
+            "wall_post_new" => Ok(Event::WallPostNew),
+            "wall_reply_new" => Ok(Event::WallReplyNew),
+            "group_join" => Ok(Event::GroupJoin),
+            "group_leave" => Ok(Event::GroupLeave),
+            "photo_new" => Ok(Event::PhotoNew),
+            "like_add" => Ok(Event::LikeAdd),
+            "like_remove" => Ok(Event::LikeRemove),
+            "user_block" => Ok(Event::UserBlock),
+            "user_unblock" => Ok(Event::UserUnblock),
+            "poll_vote_new" => Ok(Event::PollVoteNew),
+            "group_officers_edit" => Ok(Event::GroupOfficersEdit),
 
             "start" => Ok(Event::Start),
             "service_action" => Ok(Event::ServiceAction),
 
             "no_match" => Ok(Event::NoMatch),
+            "unknown" => Ok(Event::Unknown),
 
             _ => Err(EventFromStrError(s.into())),
         }
@@ -101,27 +195,182 @@ impl TryFrom<&str> for Event {
     }
 }
 
+/// Object-safe combination of the traits required from errors of handlers.
+trait ErrorObject: Debug + Display + Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<E> ErrorObject for E
+where
+    E: Debug + Display + Send + Sync + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Error returned by a failed [`Handler`], see [`Core::on_error`].
+///
+/// Wraps any error type implementing [`Debug`] and [`Display`], e.g. the one
+/// returned by [`Context::send`].
+pub struct HandlerError {
+    inner: Box<dyn ErrorObject>,
+}
+
+impl HandlerError {
+    /// Creates a new [`HandlerError`]. A [`HandlerError`] passed here is
+    /// returned as is.
+    pub fn new<E>(error: E) -> Self
+    where
+        E: Debug + Display + Send + Sync + 'static,
+    {
+        let mut error = Some(error);
+        if let Some(error) = (&mut error as &mut dyn Any).downcast_mut::<Option<HandlerError>>() {
+            return error.take().expect("error was already taken");
+        }
+
+        Self {
+            inner: Box::new(error.expect("error was already taken")),
+        }
+    }
+
+    /// Returns a reference to the wrapped error if it is of type `E`.
+    pub fn downcast_ref<E: 'static>(&self) -> Option<&E> {
+        (*self.inner).as_any().downcast_ref()
+    }
+}
+
+impl Debug for HandlerError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        Debug::fmt(&self.inner, f)
+    }
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        Display::fmt(&self.inner, f)
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+/// Types that can be returned by handler functions: `()` for handlers that
+/// cannot fail, and `Result<(), E>` for ones that can.
+pub trait HandlerResult {
+    /// Converts this value into the result of a [`Handler`].
+    fn into_result(self) -> Result<(), HandlerError>;
+}
+
+impl HandlerResult for () {
+    fn into_result(self) -> Result<(), HandlerError> {
+        Ok(())
+    }
+}
+
+impl<E> HandlerResult for Result<(), E>
+where
+    E: Debug + Display + Send + Sync + 'static,
+{
+    fn into_result(self) -> Result<(), HandlerError> {
+        self.map_err(HandlerError::new)
+    }
+}
+
+/// Future returned by a [`Handler`].
+pub type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send + 'a>>;
+
 /// Inner type of [`Handler`].
-pub type HandlerInner = Arc<dyn Fn(&mut Context) + Send + Sync + 'static>;
+pub type HandlerInner =
+    Arc<dyn for<'a> Fn(&'a mut Context) -> HandlerFuture<'a> + Send + Sync + 'static>;
 
 /// Handler's [`Fn`] should handle the message/event using the given `&mut`
 /// [`Context`], and return it back when finished.
 ///
-/// This is essentially a wrapper around `Arc<dyn Fn(&mut Context) + ...>`.
+/// The function may return either `()` or `Result<(), E>` (see
+/// [`HandlerResult`]); errors are passed to the handler set up via
+/// [`Core::on_error`].
+///
+/// This is essentially a wrapper around
+/// `Arc<dyn Fn(&mut Context) -> HandlerFuture + ...>`.
 #[derive(Clone)]
 pub struct Handler {
     inner: HandlerInner,
+    guards: Vec<Guard>,
 }
 
 impl Handler {
-    /// Creates a new wrapper.
-    pub fn new<F>(handler: F) -> Self
+    /// Creates a new wrapper around a synchronous handler.
+    ///
+    /// Synchronous handlers run directly on the async runtime, so they must
+    /// not block: use [`Handler::new_async`] for handlers which send messages
+    /// or call the API.
+    pub fn new<F, R>(handler: F) -> Self
     where
-        F: Fn(&mut Context) + Send + Sync + 'static,
+        F: Fn(&mut Context) -> R + Send + Sync + 'static,
+        R: HandlerResult,
     {
         Self {
-            inner: Arc::new(handler),
+            inner: Arc::new(move |ctx| Box::pin(future::ready(handler(ctx).into_result()))),
+            guards: Vec::new(),
+        }
+    }
+
+    /// Creates a new wrapper around an asynchronous handler.
+    ///
+    /// The handler receives its own clone of the [`Context`], so the returned
+    /// future may be `'static`.
+    ///
+    /// # Example
+    /// ```
+    /// # use vk_bot::Handler;
+    /// Handler::new_async(|mut ctx| async move {
+    ///     ctx.response().set_message("Hi!");
+    ///     ctx.send().await
+    /// });
+    /// ```
+    pub fn new_async<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerResult,
+    {
+        Self {
+            inner: Arc::new(move |ctx| {
+                let fut = handler(ctx.clone());
+                Box::pin(async move { fut.await.into_result() })
+            }),
+            guards: Vec::new(),
+        }
+    }
+
+    /// Adds a [`Guard`] to this handler. The handler is only called if all of
+    /// its guards allow it; otherwise, routing falls through to the next
+    /// matching handler, as if this one did not match.
+    ///
+    /// # Example
+    /// ```
+    /// # use vk_bot::{Core, Guard, Handler};
+    /// Core::new().cmd(
+    ///     "kick",
+    ///     Handler::new_async(|ctx| async move { ctx.send().await })
+    ///         .guard(Guard::chat())
+    ///         .guard(Guard::chat_admins()),
+    /// );
+    /// ```
+    pub fn guard(mut self, guard: Guard) -> Self {
+        self.guards.push(guard);
+        self
+    }
+
+    /// Returns whether the guards of this handler allow it to handle the event.
+    async fn allows(&self, ctx: &Context) -> bool {
+        for guard in &self.guards {
+            if !guard(ctx).await {
+                trace!("guard not passed for {:#?}", ctx);
+                return false;
+            }
         }
+        true
     }
 }
 
@@ -138,6 +387,77 @@ impl Debug for Handler {
     }
 }
 
+/// Future returned by an [`ErrorHandler`].
+pub type ErrorHandlerFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Inner type of [`ErrorHandler`].
+pub type ErrorHandlerInner = Arc<
+    dyn for<'a> Fn(HandlerError, &'a mut Context) -> ErrorHandlerFuture<'a> + Send + Sync + 'static,
+>;
+
+/// ErrorHandler's [`Fn`] should handle the error returned by a [`Handler`],
+/// using the [`Context`] the failed handler was given. See
+/// [`Core::on_error`].
+///
+/// This is essentially a wrapper around
+/// `Arc<dyn Fn(HandlerError, &mut Context) -> ErrorHandlerFuture + ...>`.
+#[derive(Clone)]
+pub struct ErrorHandler {
+    inner: ErrorHandlerInner,
+}
+
+impl ErrorHandler {
+    /// Creates a new wrapper around a synchronous error handler.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&HandlerError, &mut Context) + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(move |e, ctx| {
+                handler(&e, ctx);
+                Box::pin(future::ready(()))
+            }),
+        }
+    }
+
+    /// Creates a new wrapper around an asynchronous error handler.
+    ///
+    /// The handler receives its own clone of the [`Context`], so the returned
+    /// future may be `'static`.
+    ///
+    /// # Example
+    /// ```
+    /// # use vk_bot::core::ErrorHandler;
+    /// ErrorHandler::new_async(|e, mut ctx| async move {
+    ///     eprintln!("handler failed: {}", e);
+    ///     ctx.response().set_message("Something went wrong, sorry!");
+    ///     let _ = ctx.send().await;
+    /// });
+    /// ```
+    pub fn new_async<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(HandlerError, Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            inner: Arc::new(move |e, ctx| Box::pin(handler(e, ctx.clone()))),
+        }
+    }
+}
+
+impl Deref for ErrorHandler {
+    type Target = ErrorHandlerInner;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl Debug for ErrorHandler {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str("ErrorHandler {...}")
+    }
+}
+
 /// Inner type of [`Tester`].
 pub type TesterInner = Arc<dyn (Fn(&String) -> bool) + Send + Sync + 'static>;
 
@@ -150,8 +470,15 @@ pub type TesterInner = Arc<dyn (Fn(&String) -> bool) + Send + Sync + 'static>;
 #[derive(Clone)]
 pub struct Tester {
     inner: TesterInner,
+    parser: Option<Parser>,
 }
 
+/// Deserializes a payload for a [`Core::typed_payload`] handler.
+type Parser = Arc<dyn (Fn(&str) -> Option<ParsedPayload>) + Send + Sync + 'static>;
+
+/// A payload deserialized by a [`Tester`] created by [`Core::typed_payload`].
+pub(crate) type ParsedPayload = Box<dyn Any + Send>;
+
 impl Tester {
     /// Creates a new wrapper.
     pub fn new<F>(tester: F) -> Self
@@ -160,6 +487,36 @@ impl Tester {
     {
         Self {
             inner: Arc::new(tester),
+            parser: None,
+        }
+    }
+
+    /// Creates a tester which accepts payloads that can be deserialized into
+    /// `T`.
+    fn typed<T>() -> Self
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let parser: Parser = Arc::new(|payload| {
+            let payload = serde_json::from_str::<T>(payload).ok()?;
+            Some(Box::new(payload))
+        });
+
+        Self {
+            inner: {
+                let parser = Arc::clone(&parser);
+                Arc::new(move |payload| parser(payload).is_some())
+            },
+            parser: Some(parser),
+        }
+    }
+
+    /// Tests a payload. Returns `Some` if the payload is accepted, with the
+    /// deserialized payload for testers created by [`Core::typed_payload`].
+    fn test(&self, payload: &String) -> Option<Option<ParsedPayload>> {
+        match &self.parser {
+            Some(parser) => parser(payload).map(Some),
+            None => (self.inner)(payload).then_some(None),
         }
     }
 }
@@ -177,6 +534,189 @@ impl Debug for Tester {
     }
 }
 
+/// Inner type of [`ContextErrorHandler`].
+pub type ContextErrorHandlerInner =
+    Arc<dyn Fn(&ContextError, &CallbackAPIRequest) + Send + Sync + 'static>;
+
+/// ContextErrorHandler's [`Fn`] is called with the error and the request when
+/// a [`Context`] could not be created for it. See [`Core::on_context_error`].
+///
+/// This is essentially a wrapper around
+/// `Arc<dyn Fn(&ContextError, &CallbackAPIRequest) + ...>`.
+#[derive(Clone)]
+pub struct ContextErrorHandler {
+    inner: ContextErrorHandlerInner,
+}
+
+impl ContextErrorHandler {
+    /// Creates a new wrapper.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&ContextError, &CallbackAPIRequest) + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(handler),
+        }
+    }
+}
+
+impl Deref for ContextErrorHandler {
+    type Target = ContextErrorHandlerInner;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl Debug for ContextErrorHandler {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str("ContextErrorHandler {...}")
+    }
+}
+
+/// Inner type of [`Middleware`].
+pub type MiddlewareInner =
+    Arc<dyn for<'a> Fn(&'a mut Context, Next<'a>) -> HandlerFuture<'a> + Send + Sync + 'static>;
+
+/// Middleware's [`Fn`] is called with the [`Context`] and a [`Next`]
+/// continuation before the [`Handler`] found for an event. It may modify the
+/// context, run code before and after the handler by calling [`Next::run`],
+/// or not call it at all to stop the event from being handled. See
+/// [`Core::wrap`].
+///
+/// This is essentially a wrapper around
+/// `Arc<dyn Fn(&mut Context, Next) -> HandlerFuture + ...>`.
+#[derive(Clone)]
+pub struct Middleware {
+    inner: MiddlewareInner,
+}
+
+impl Middleware {
+    /// Creates a new wrapper.
+    ///
+    /// # Example
+    /// ```
+    /// # use vk_bot::core::Middleware;
+    /// # use std::time::Instant;
+    /// Middleware::new(|ctx, next| {
+    ///     Box::pin(async move {
+    ///         let start = Instant::now();
+    ///         let result = next.run(ctx).await;
+    ///         println!("handled in {:?}", start.elapsed());
+    ///         result
+    ///     })
+    /// });
+    /// ```
+    pub fn new<F>(middleware: F) -> Self
+    where
+        F: for<'a> Fn(&'a mut Context, Next<'a>) -> HandlerFuture<'a> + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(middleware),
+        }
+    }
+}
+
+impl Deref for Middleware {
+    type Target = MiddlewareInner;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl Debug for Middleware {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str("Middleware {...}")
+    }
+}
+
+/// The rest of the chain of [`Middleware`]s, ending with the [`Handler`]
+/// found for an event.
+pub struct Next<'a> {
+    middleware: &'a [Middleware],
+    handler: &'a Handler,
+}
+
+impl<'a> Next<'a> {
+    /// Calls the next [`Middleware`], or the [`Handler`] if there are no more
+    /// middleware left.
+    pub fn run(self, ctx: &'a mut Context) -> HandlerFuture<'a> {
+        match self.middleware.split_first() {
+            Some((middleware, rest)) => middleware(
+                ctx,
+                Next {
+                    middleware: rest,
+                    handler: self.handler,
+                },
+            ),
+            None => (self.handler)(ctx),
+        }
+    }
+}
+
+impl Debug for Next<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str("Next {...}")
+    }
+}
+
+/// Calls handlers with the middleware and the error handler of a [`Core`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Dispatcher {
+    middleware: Arc<Vec<Middleware>>,
+    error_handler: Option<ErrorHandler>,
+}
+
+impl Dispatcher {
+    /// Calls a handler with the middleware, and passes its error to the error
+    /// handler. Returns the error if there is no error handler.
+    pub(crate) async fn call(
+        &self,
+        handler: &Handler,
+        ctx: &mut Context,
+    ) -> Result<(), HandlerError> {
+        let next = Next {
+            middleware: &self.middleware,
+            handler,
+        };
+
+        match (next.run(ctx).await, &self.error_handler) {
+            (Err(e), Some(error_handler)) => {
+                error_handler(e, ctx).await;
+                Ok(())
+            }
+            (result, _) => result,
+        }
+    }
+}
+
+/// Where a [`Core`] is mounted, see [`Core::mount`].
+#[derive(Debug, Clone)]
+pub enum Mount {
+    /// Messages starting with the prefix (followed by whitespace or the end of
+    /// the text), e.g. `/admin`.
+    Prefix(String),
+    /// Events allowed by the guard.
+    Guard(Guard),
+}
+
+impl From<&str> for Mount {
+    fn from(prefix: &str) -> Self {
+        Mount::Prefix(prefix.into())
+    }
+}
+
+impl From<String> for Mount {
+    fn from(prefix: String) -> Self {
+        Mount::Prefix(prefix)
+    }
+}
+
+impl From<Guard> for Mount {
+    fn from(guard: Guard) -> Self {
+        Mount::Guard(guard)
+    }
+}
+
 /// [`Core`] accepts user-defined handlers, and invokes them when needed.
 /// Note that only one handler (the first found, according to the
 /// [`Core::on`] docs) is called for a given message.
@@ -184,23 +724,51 @@ impl Debug for Tester {
 /// Works like a builder.
 #[derive(Debug, Clone)]
 pub struct Core {
+    name: Option<String>,
+    module: Option<String>,
     cmd_prefix: Option<String>,
     event_handlers: HashMap<Event, Handler>,
-    static_payload_handlers: HashMap<String, Handler>,
+    action_handlers: HashMap<ActionKind, Handler>,
+    static_payload_handlers: Vec<(Value, Handler)>,
     dyn_payload_handlers: Vec<(Tester, Handler)>,
-    command_handlers: HashMap<String, Handler>,
-    regex_handlers: Vec<(Regex, Handler)>,
+    commands: Vec<Command>,
+    command_table: OnceLock<CommandTable>,
+    regex_handlers: Vec<(Option<String>, Regex, Handler)>,
+    regex_table: OnceLock<RegexTable>,
+    mounted_no_match_handlers: Vec<(Option<String>, Handler)>,
+    states: HashMap<String, Core>,
+    state_store: States,
+    state_timeout: Option<(Duration, Handler)>,
+    sessions: Sessions,
+    middleware: Arc<Vec<Middleware>>,
+    error_handler: Option<ErrorHandler>,
+    context_error_handler: Option<ContextErrorHandler>,
+    reply_handler: Handler,
 }
 
 impl Default for Core {
     fn default() -> Self {
         Self {
+            name: None,
+            module: None,
             cmd_prefix: None,
             event_handlers: Default::default(),
+            action_handlers: Default::default(),
             static_payload_handlers: Default::default(),
             dyn_payload_handlers: Default::default(),
-            command_handlers: Default::default(),
+            commands: Default::default(),
+            command_table: Default::default(),
             regex_handlers: Default::default(),
+            regex_table: Default::default(),
+            mounted_no_match_handlers: Default::default(),
+            states: Default::default(),
+            state_store: Default::default(),
+            state_timeout: None,
+            sessions: Default::default(),
+            middleware: Default::default(),
+            error_handler: None,
+            context_error_handler: None,
+            reply_handler: Handler::new_async(|ctx| async move { ctx.send().await }),
         }
     }
 }
@@ -211,12 +779,60 @@ impl Core {
         Default::default()
     }
 
+    /// Sets the name of this [`Core`], used in panic messages when it is
+    /// mounted (see [`Core::mount`]).
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
     /// Modifies this [`Core`]'s command prefix.
     pub fn cmd_prefix(mut self, cmd_prefix: &str) -> Self {
         self.cmd_prefix = Some(cmd_prefix.into());
+        self.command_table = Default::default();
+        for state in self.states.values_mut() {
+            state.cmd_prefix = self.cmd_prefix.clone();
+            state.command_table = Default::default();
+        }
+        self
+    }
+
+    /// Compiles the commands and the regexes of this [`Core`] (and of its
+    /// states) for matching.
+    ///
+    /// [`Bot`](crate::Bot) builds its `Core`s when it is built. Otherwise, a
+    /// `Core` is built when it handles the first message.
+    pub fn build(self) -> Self {
+        self.compile();
         self
     }
 
+    /// Compiles the commands and the regexes, unless they are compiled
+    /// already.
+    fn compile(&self) {
+        self.command_table();
+        self.regex_table();
+        for core in self.states.values() {
+            core.compile();
+        }
+    }
+
+    /// Returns the compiled commands.
+    fn command_table(&self) -> &CommandTable {
+        self.command_table.get_or_init(|| {
+            CommandTable::new(
+                &self.commands,
+                self.cmd_prefix.as_deref().unwrap_or_default(),
+            )
+        })
+    }
+
+    /// Returns the compiled regexes.
+    fn regex_table(&self) -> &RegexTable {
+        self.regex_table
+            .get_or_init(|| RegexTable::new(&self.regex_handlers))
+    }
+
     /// Adds a new event handler to this [`Core`].
     ///
     /// See [`Event`] for possible events.
@@ -229,13 +845,34 @@ impl Core {
     ///
     /// \# | cause | action
     /// ---|---|---
-    /// 1 | `action` field on object | [`Event::ServiceAction`]
+    /// 1 | `action` field on object | [`Core::on_action`] or [`Event::ServiceAction`]
     /// 2 | special `{"command": "start"}` payload | [`Event::Start`]
     /// 3 | exact payload match set up via [`Core::payload`] | respective handler
     /// 4 | 'dynamic' payload match set up via [`Core::dyn_payload`] | respective handler
     /// 5 | command handlers ([`Core::cmd_prefix`] and [`Core::cmd`]) | respective handler
     /// 6 | regex handlers ([`Core::regex`]) | respective handler
     /// 7 | anything except [`Event::MessageReply`] and [`Event::NoMatch`] | [`Event::NoMatch`]
+    ///
+    /// Handlers whose guards do not allow the event (see [`Handler::guard`])
+    /// are skipped, as if they did not match.
+    ///
+    /// Events from conversations in a state (see [`Core::state`]) are routed
+    /// to the handlers of the state first, in the same way.
+    ///
+    /// [`Event::MessageEvent`] is handled by the payload handlers ([`Core::payload`]
+    /// and [`Core::dyn_payload`]), then by the [`Event::MessageEvent`] handler, and
+    /// finally by the [`Event::NoMatch`] handler. Such events should be answered
+    /// using [`Context::answer_event`].
+    ///
+    /// Community events, such as [`Event::WallPostNew`], are never handled by
+    /// [`Event::NoMatch`]. Their objects can be parsed using
+    /// [`Object::parse`](crate::request::Object::parse) and the structs in
+    /// the [`objects`](crate::objects) module.
+    ///
+    /// Events of types unknown to this crate are logged and handled by the
+    /// [`Event::Unknown`] handler, which can inspect the raw
+    /// [`Context::object`]. They are never handled by [`Event::NoMatch`]
+    /// either.
     pub fn on(mut self, event: Event, handler: Handler) -> Self {
         let entry = self.event_handlers.entry(event);
 
@@ -247,7 +884,11 @@ impl Core {
             ),
             _ => match entry {
                 Entry::Occupied(_) => {
-                    panic!("attempt to set up duplicate handler for event `{}`", event)
+                    panic!(
+                        "attempt to set up duplicate handler for event `{}`{}",
+                        event,
+                        self.module()
+                    )
                 }
                 Entry::Vacant(entry) => {
                     entry.insert(handler);
@@ -258,22 +899,98 @@ impl Core {
         self
     }
 
-    /// Adds a new payload handler to this [`Core`].
+    /// Adds a new handler for service messages with the given kind of action
+    /// (see [`Context::action`]) to this [`Core`].
     ///
-    /// See also [`Core::dyn_payload`].
-    pub fn payload(mut self, payload: &str, handler: Handler) -> Self {
-        let entry = self.static_payload_handlers.entry(payload.into());
+    /// Service messages without a matching action handler are handled by the
+    /// [`Event::ServiceAction`] handler.
+    pub fn on_action(mut self, kind: ActionKind, handler: Handler) -> Self {
+        let entry = self.action_handlers.entry(kind);
         match entry {
-            Entry::Occupied(_) => panic!(
-                "attempt to set up duplicate handler for payload {:#?}",
-                payload
-            ),
+            Entry::Occupied(_) => {
+                panic!(
+                    "attempt to set up duplicate handler for action `{}`{}",
+                    kind,
+                    self.module()
+                )
+            }
             Entry::Vacant(entry) => entry.insert(handler),
         };
 
         self
     }
 
+    /// Adds a new payload handler to this [`Core`].
+    ///
+    /// Payloads are compared as parsed JSON values, so key order and
+    /// whitespace do not matter: `{"a":"b","c":1}` matches `{"c": 1, "a": "b"}`.
+    /// Payloads which are not valid JSON are compared as strings.
+    ///
+    /// See also [`Core::dyn_payload`] and [`Core::typed_payload`].
+    pub fn payload(self, payload: &str, handler: Handler) -> Self {
+        self.static_payload(parse_payload(payload), handler)
+    }
+
+    /// Adds a new payload handler for a parsed payload to this [`Core`].
+    fn static_payload(mut self, value: Value, handler: Handler) -> Self {
+        if self
+            .static_payload_handlers
+            .iter()
+            .any(|(existing, _)| existing == &value)
+        {
+            panic!(
+                "attempt to set up duplicate handler for payload {:#?}{}",
+                value.to_string(),
+                self.module()
+            );
+        }
+
+        self.static_payload_handlers.push((value, handler));
+        self
+    }
+
+    /// Adds a new payload handler to this [`Core`], which is used when the
+    /// payload can be deserialized into `T`. The handler receives its own
+    /// clone of the [`Context`] (like [`Handler::new_async`]) and the
+    /// deserialized payload.
+    ///
+    /// Typed payload handlers are tried in order together with the
+    /// [`Core::dyn_payload`] handlers.
+    ///
+    /// # Example
+    /// ```
+    /// # use serde_derive::Deserialize;
+    /// # use vk_bot::Core;
+    /// #[derive(Deserialize)]
+    /// struct Buy {
+    ///     item: u32,
+    /// }
+    ///
+    /// Core::new().typed_payload(|mut ctx, buy: Buy| async move {
+    ///     ctx.response().set_message(&format!("Bought item #{}!", buy.item));
+    ///     ctx.send().await
+    /// });
+    /// ```
+    pub fn typed_payload<T, F, Fut>(self, handler: F) -> Self
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(Context, T) -> Fut + Send + Sync + 'static,
+        Fut: Future + Send + 'static,
+        Fut::Output: HandlerResult,
+    {
+        let handler = Handler::new_async(move |ctx| {
+            // The payload is deserialized once by the tester, and set on the
+            // context before the handler is called.
+            let payload = ctx
+                .take_parsed_payload()
+                .and_then(|payload| payload.downcast::<T>().ok())
+                .expect("typed payload handler called without a deserialized payload");
+            handler(ctx, *payload)
+        });
+
+        self.dyn_payload(Tester::typed::<T>(), handler)
+    }
+
     /// Adds a new dynamic (provided a [`Tester`]) payload handler to this
     /// [`Core`].
     ///
@@ -285,197 +1002,1933 @@ impl Core {
 
     /// Adds a new command (exact string after command prefix) handler to this
     /// [`Core`].
-    pub fn cmd(mut self, cmd: &str, handler: Handler) -> Self {
-        let entry = self.command_handlers.entry(cmd.into());
-        match entry {
-            Entry::Occupied(_) => {
-                panic!("attempt to set up duplicate handler for command `{}`", cmd);
-            }
-            Entry::Vacant(entry) => entry.insert(handler),
-        };
+    ///
+    /// Any text after the command is accepted, and is available via
+    /// [`Args::rest`](crate::command::Args::rest). See [`Core::command`] for
+    /// commands with declared arguments.
+    pub fn cmd(self, cmd: &str, handler: Handler) -> Self {
+        self.command(Command::new(cmd, handler).rest("args"))
+    }
 
+    /// Adds a new command with declared arguments to this [`Core`].
+    ///
+    /// A message matches a command if it starts (optionally after a mention
+    /// of the community) with the command prefix and the name or an alias of
+    /// the command, followed by a word boundary. If several commands match,
+    /// the longest match wins, and then the command set up first; if the
+    /// guards of its handler fail, the next best match is tried. Commands are
+    /// compiled into a single [`RegexSet`] (see [`Core::build`]).
+    ///
+    /// See the [`command`](crate::command) module for descriptions, aliases,
+    /// case-insensitive matching and the built-in help command.
+    ///
+    /// The arguments are available via [`Context::args`]. If they do not
+    /// match the declaration, the handler is not called; instead, the error
+    /// and the [usage](Command::usage) of the command are sent in reply.
+    pub fn command(mut self, command: Command) -> Self {
+        if let Some(name) = self
+            .commands
+            .iter()
+            .find_map(|existing| existing.conflicts_with(&command))
+        {
+            panic!(
+                "attempt to set up duplicate handler for command `{}`{}",
+                name,
+                self.module()
+            );
+        }
+
+        self.commands.push(command);
+        self.command_table = Default::default();
         self
     }
 
     /// Adds a new regex handler to this [`Core`].
+    ///
+    /// Regex handlers are tried in the order they were set up. The groups
+    /// captured by the regex are available via [`Context::captures`] and
+    /// [`Context::capture`].
+    ///
+    /// Regexes are compiled into a [`RegexSet`] (see [`Core::build`]) from
+    /// their patterns, so options set via
+    /// [`RegexBuilder`](regex::RegexBuilder) are not kept; use inline flags
+    /// like `(?i)` instead.
     pub fn regex(mut self, re: Regex, handler: Handler) -> Self {
-        self.regex_handlers.push((re, handler));
+        self.regex_handlers.push((None, re, handler));
+        self.regex_table = Default::default();
         self
     }
 
-    /// Handles a request by telling the appropriate [`Handler`] to do so.
-    pub fn handle(&self, req: &CallbackAPIRequest, api: &APIClient) {
-        trace!("handling {:#?}", req);
-
-        let event: Event = req.r#type().parse().expect("error while handling request");
-        let mut ctx = Context::new(event, req, api);
-        self.handle_event(event, &mut ctx);
-    }
-
-    /// Handles an event.
-    fn handle_event(&self, event: Event, ctx: &mut Context) {
-        debug!("handling event `{}`", event);
-        match event {
-            Event::MessageNew => self.handle_message_new(ctx),
-            Event::NoMatch => {
-                if let Some(handler) = self.event_handlers.get(&Event::NoMatch) {
-                    handler(ctx)
+    /// Mounts another [`Core`] into this one, so that separately built parts
+    /// of a bot can be combined.
+    ///
+    /// When mounted at a prefix (`&str`), commands of `core` are matched after
+    /// the prefix and a space (`/admin ban 5` for the `ban` command of `core`
+    /// with the `/` command prefix mounted at `/admin`), and regexes of `core`
+    /// are matched against the text after the prefix. When mounted with a
+    /// [`Guard`], every handler of `core` is guarded by it, and commands of
+    /// `core` use the command prefix of this `Core` unless `core` has its own
+    /// (see [`Core::cmd_prefix`]).
+    ///
+    /// Handlers of `core` are set up in this `Core` after the existing ones,
+    /// and are wrapped with the middleware and the error handler of `core`.
+    /// The [`Event::NoMatch`] handler of `core` handles messages with the
+    /// prefix (or allowed by the guard) not matched by `core`, after all regex
+    /// handlers of this `Core` (including ones set up later). Other event,
+    /// action and payload handlers of `core` are not affected by the prefix.
+    ///
+    /// # Example
+    /// ```
+    /// # use vk_bot::{Core, Guard, Handler};
+    /// let admin = Core::new()
+    ///     .name("admin")
+    ///     .cmd_prefix("")
+    ///     .cmd("ban", Handler::new(|_| {}));
+    /// let chat = Core::new().cmd_prefix("/").cmd("poll", Handler::new(|_| {}));
+    ///
+    /// Core::new()
+    ///     .cmd_prefix("/")
+    ///     .mount("/admin", admin)
+    ///     .mount(Guard::chat(), chat);
+    /// ```
+    ///
+    /// # Panics
+    /// - if `core` has a handler conflicting with one of this `Core`; the
+    ///   panic message names the mounted module (see [`Core::name`]).
+    pub fn mount<M: Into<Mount>>(self, mount: M, core: Core) -> Self {
+        let mount = mount.into();
+        let (prefix, guard) = match &mount {
+            Mount::Prefix(prefix) => (Some(prefix.as_str()), None),
+            Mount::Guard(guard) => (None, Some(guard)),
+        };
+
+        let module = match (&core.name, prefix) {
+            (Some(name), _) => format!("module `{}`", name),
+            (None, Some(prefix)) => format!("module mounted at `{}`", prefix),
+            (None, None) => "module mounted with a guard".into(),
+        };
+
+        self.merge(module, prefix, guard, core)
+    }
+
+    /// Sets up the handlers of another [`Core`] in this one, see
+    /// [`Core::mount`]. `module` describes `core` in panic messages.
+    fn merge(
+        mut self,
+        module: String,
+        prefix: Option<&str>,
+        guard: Option<&Guard>,
+        mut core: Core,
+    ) -> Self {
+        let outer = self.module.replace(module);
+
+        let middleware = std::mem::take(&mut core.middleware);
+        let error_handler = core.error_handler.take();
+        let wrap =
+            |handler| Self::mounted_handler(handler, &middleware, error_handler.as_ref(), guard);
+        self.merge_states(&mut core, wrap);
+
+        let no_match = core.event_handlers.remove(&Event::NoMatch);
+        for (event, handler) in core.event_handlers {
+            self = self.on(event, wrap(handler));
+        }
+        for (kind, handler) in core.action_handlers {
+            self = self.on_action(kind, wrap(handler));
+        }
+        for (value, handler) in core.static_payload_handlers {
+            self = self.static_payload(value, wrap(handler));
+        }
+        for (tester, handler) in core.dyn_payload_handlers {
+            self = self.dyn_payload(tester, wrap(handler));
+        }
+
+        for mut command in core.commands {
+            command.mount(prefix, core.cmd_prefix.as_deref());
+            *command.handler_mut() = wrap(command.handler().clone());
+            self = self.command(command);
+        }
+
+        let mount_prefix = |inner: Option<String>| match (prefix, inner) {
+            (Some(prefix), Some(inner)) => Some(format!("{} {}", prefix, inner)),
+            (prefix, inner) => inner.or_else(|| prefix.map(Into::into)),
+        };
+        for (inner, re, handler) in core.regex_handlers {
+            self.regex_handlers
+                .push((mount_prefix(inner), re, wrap(handler)));
+        }
+        self.regex_table = Default::default();
+
+        // Mounted `NoMatch` handlers are tried after all regex handlers, the
+        // innermost ones first.
+        for (inner, handler) in core.mounted_no_match_handlers {
+            self.mounted_no_match_handlers
+                .push((mount_prefix(inner), wrap(handler)));
+        }
+        if let Some(handler) = no_match {
+            self.mounted_no_match_handlers
+                .push((prefix.map(Into::into), wrap(handler)));
+        }
+
+        self.module = outer;
+        self
+    }
+
+    /// Adds a new state to this [`Core`]. Messages from conversations in this
+    /// state (see [`Context::set_state`]) are routed to the handlers of `core`
+    /// first, and then, if none of them matches, to the handlers of this
+    /// `Core`.
+    ///
+    /// The handlers of `core` are set up like for [`Core::mount`] without a
+    /// prefix or a guard: its [`Event::NoMatch`] handler handles the messages
+    /// not matched by `core`, and its commands use the command prefix of this
+    /// `Core` unless `core` has its own. The states of `core` are added to
+    /// this `Core`. See [`Core::state_timeout`] for states which expire.
+    ///
+    /// # Example
+    /// ```
+    /// # use vk_bot::{Core, Event, Handler};
+    /// # use std::time::Duration;
+    /// Core::new()
+    ///     .cmd_prefix("/")
+    ///     .cmd(
+    ///         "register",
+    ///         Handler::new_async(|mut ctx| async move {
+    ///             ctx.set_state("awaiting_email");
+    ///             ctx.response().set_message("What is your email?");
+    ///             ctx.send().await
+    ///         }),
+    ///     )
+    ///     .state(
+    ///         "awaiting_email",
+    ///         Core::new()
+    ///             .on(
+    ///                 Event::NoMatch,
+    ///                 Handler::new_async(|mut ctx| async move {
+    ///                     ctx.clear_state();
+    ///                     ctx.response().set_message("Thanks!");
+    ///                     ctx.send().await
+    ///                 }),
+    ///             )
+    ///             .state_timeout(
+    ///                 Duration::from_secs(600),
+    ///                 Handler::new_async(|mut ctx| async move {
+    ///                     ctx.response().set_message("Registration cancelled.");
+    ///                     ctx.send().await
+    ///                 }),
+    ///             ),
+    ///     );
+    /// ```
+    ///
+    /// # Panics
+    /// - if a state with this name is already set up.
+    pub fn state(mut self, name: &str, mut core: Core) -> Self {
+        if let Some((timeout, handler)) = core.state_timeout.take() {
+            let handler =
+                Self::mounted_handler(handler, &core.middleware, core.error_handler.as_ref(), None);
+            self.state_store.set_timeout(name, timeout, handler);
+        }
+
+        let module = match &core.name {
+            Some(core_name) => format!("module `{}`", core_name),
+            None => format!("state `{}`", name),
+        };
+        let mut core = Core::new().merge(module, None, None, core);
+        self.merge_states(&mut core, |handler| handler);
+        core.cmd_prefix = self.cmd_prefix.clone();
+
+        if self.states.contains_key(name) {
+            panic!(
+                "attempt to set up duplicate state `{}`{}",
+                name,
+                self.module()
+            );
+        }
+        self.states.insert(name.into(), core);
+
+        self
+    }
+
+    /// Sets which events share a state (see [`Core::state`] and
+    /// [`Context::set_state`]).
+    ///
+    /// By default, a state is set for a user in a conversation, so that in a
+    /// group chat, messages of other members are not routed to the handlers
+    /// of the state.
+    pub fn state_scope(mut self, scope: SessionScope) -> Self {
+        self.state_store.set_scope(scope);
+        self
+    }
+
+    /// Sets the timeout of the state this [`Core`] is set up for (see
+    /// [`Core::state`]). Once a conversation has been in the state for
+    /// `timeout`, the state is cleared and `handler` is called with the
+    /// [`Context`] of the message which set the state (see
+    /// [`Context::set_state`]).
+    ///
+    /// Like other handlers, `handler` is wrapped with the middleware and the
+    /// error handler of the state's `Core` and of the `Core` handling the
+    /// message (see [`Core::wrap`] and [`Core::on_error`]).
+    ///
+    /// The timeout is cancelled when the state is changed or cleared, and
+    /// when the [`Bot`](crate::Bot) is stopped.
+    pub fn state_timeout(mut self, timeout: Duration, handler: Handler) -> Self {
+        self.state_timeout = Some((timeout, handler));
+        self
+    }
+
+    /// Moves the states of another [`Core`] to this one, wrapping their
+    /// timeout handlers using `wrap`.
+    fn merge_states<F>(&mut self, core: &mut Core, wrap: F)
+    where
+        F: Fn(Handler) -> Handler,
+    {
+        for (name, mut state) in std::mem::take(&mut core.states) {
+            if self.states.contains_key(&name) {
+                panic!(
+                    "attempt to set up duplicate state `{}`{}",
+                    name,
+                    self.module()
+                );
+            }
+
+            // Commands of the state keep the command prefix of `core`, if it
+            // has one, and use the one of this `Core` otherwise.
+            for command in &mut state.commands {
+                command.mount(None, core.cmd_prefix.as_deref());
+            }
+            state.cmd_prefix = self.cmd_prefix.clone();
+            state.command_table = Default::default();
+            self.states.insert(name, state);
+        }
+
+        for (name, (timeout, handler)) in core.state_store.timeouts() {
+            self.state_store
+                .set_timeout(name, *timeout, wrap(handler.clone()));
+        }
+    }
+
+    /// Wraps a handler of a mounted [`Core`] with its middleware and error
+    /// handler, and adds the guard of the mount.
+    fn mounted_handler(
+        handler: Handler,
+        middleware: &Arc<Vec<Middleware>>,
+        error_handler: Option<&ErrorHandler>,
+        guard: Option<&Guard>,
+    ) -> Handler {
+        let guards = guard
+            .into_iter()
+            .cloned()
+            .chain(handler.guards.clone())
+            .collect();
+        if middleware.is_empty() && error_handler.is_none() {
+            return Handler { guards, ..handler };
+        }
+
+        let handler = Arc::new(handler);
+        let dispatcher = Dispatcher {
+            middleware: Arc::clone(middleware),
+            error_handler: error_handler.cloned(),
+        };
+
+        Handler {
+            inner: Arc::new(move |ctx| {
+                let handler = Arc::clone(&handler);
+                let dispatcher = dispatcher.clone();
+                Box::pin(async move { dispatcher.call(&handler, ctx).await })
+            }),
+            guards,
+        }
+    }
+
+    /// Returns the description of the module being mounted for panic
+    /// messages, if any.
+    fn module(&self) -> String {
+        match &self.module {
+            Some(module) => format!(" (in {})", module),
+            None => String::new(),
+        }
+    }
+
+    /// Adds a new [`Middleware`] to this [`Core`], which wraps every
+    /// [`Handler`] called by it, whatever the handler was set up with.
+    ///
+    /// Middleware set up first runs first, and its [`Next`] continuation calls
+    /// the middleware set up after it. Errors returned by middleware are
+    /// handled like errors returned by handlers (see [`Core::on_error`]).
+    ///
+    /// # Example
+    /// ```
+    /// # use vk_bot::{core::Middleware, Core};
+    /// const BANNED: &[i64] = &[1, 2, 3];
+    ///
+    /// Core::new().wrap(Middleware::new(|ctx, next| {
+    ///     Box::pin(async move {
+    ///         match ctx.object().get_from_id() {
+    ///             Some(id) if BANNED.contains(id) => Ok(()),
+    ///             _ => next.run(ctx).await,
+    ///         }
+    ///     })
+    /// }));
+    /// ```
+    pub fn wrap(mut self, middleware: Middleware) -> Self {
+        Arc::make_mut(&mut self.middleware).push(middleware);
+        self
+    }
+
+    /// Sets the store and the scope of sessions (see [`Context::session`] and
+    /// the [`session`](crate::session) module).
+    ///
+    /// By default, sessions are kept in memory forever (see
+    /// [`MemorySessionStore`](crate::session::MemorySessionStore)), scoped per
+    /// user in a conversation.
+    pub fn sessions<S>(mut self, store: S, scope: SessionScope) -> Self
+    where
+        S: SessionStore + 'static,
+    {
+        self.sessions = Sessions::new(store, scope);
+        self
+    }
+
+    /// Sets the handler which is called when a [`Handler`] returns an error.
+    ///
+    /// Without it, such errors are only logged.
+    pub fn on_error(mut self, handler: ErrorHandler) -> Self {
+        self.error_handler = Some(handler);
+        self
+    }
+
+    /// Sets the handler which is called when a [`Context`] could not be created
+    /// for a request (see [`Context::new`]). Such requests are logged and not
+    /// handled otherwise.
+    pub fn on_context_error(mut self, handler: ContextErrorHandler) -> Self {
+        self.context_error_handler = Some(handler);
+        self
+    }
+
+    /// Handles a request by telling the appropriate [`Handler`] to do so.
+    pub async fn handle(&self, req: &CallbackAPIRequest, api: &Arc<APIClient>) {
+        trace!("handling {:#?}", req);
+
+        let event = req.r#type().parse().unwrap_or_else(|e| {
+            warn!("{}, handling as `{}`", e, Event::Unknown);
+            Event::Unknown
+        });
+        let mut ctx = match Context::new(event, req, Arc::clone(api)) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                error!("failed to create context: {}", e);
+                if let Some(handler) = &self.context_error_handler {
+                    handler(&e, req);
                 }
+                return;
+            }
+        };
+        ctx.set_states(self.state_store.dispatched_by(self.dispatcher()));
+        ctx.set_sessions(self.sessions.clone());
+        self.handle_event(event, &mut ctx).await;
+    }
+
+    /// Handles an event by finding the appropriate [`Handler`] and calling it.
+    async fn handle_event(&self, event: Event, ctx: &mut Context) {
+        if let Some(handler) = self.find_handler(event, ctx).await {
+            if let Err(e) = self.dispatcher().call(handler, ctx).await {
+                error!("`{}` handler failed: {}", event, e);
             }
-            e => match self.event_handlers.get(&e) {
-                Some(handler) => {
-                    trace!("calling `{}` handler for {:#?}", e, ctx);
-                    handler(ctx)
+        }
+    }
+
+    /// Runs the timeouts of states of this [`Core`] on the tracker of a
+    /// [`Bot`](crate::Bot), cancelling them once it is stopped.
+    pub(crate) fn attach(&self, tasks: &TaskTracker, stop: &CancellationToken) {
+        self.state_store.attach(tasks, stop);
+    }
+
+    /// Persists the sessions of this [`Core`], see
+    /// [`SessionStore::flush`].
+    pub(crate) fn flush_sessions(&self) {
+        self.sessions.flush();
+    }
+
+    /// Returns the [`Dispatcher`] calling handlers with the middleware and the
+    /// error handler of this [`Core`].
+    fn dispatcher(&self) -> Dispatcher {
+        Dispatcher {
+            middleware: Arc::clone(&self.middleware),
+            error_handler: self.error_handler.clone(),
+        }
+    }
+
+    /// Finds the handler for an event, trying the handlers of the state of the
+    /// conversation first.
+    async fn find_handler(&self, event: Event, ctx: &mut Context) -> Option<&Handler> {
+        debug!("handling event `{}`", event);
+        if let Some(state) = ctx.state() {
+            match self.states.get(&state) {
+                Some(core) => {
+                    trace!("trying handlers of state `{}`", state);
+                    if let Some(handler) = core.find_stateless_handler(event, ctx).await {
+                        return Some(handler);
+                    }
                 }
-                None => match e {
-                    // Prevent infinite loop when Event::MessageReply handler is not present, while
-                    // Event::NoMatch sends a message.
-                    Event::MessageReply => {}
-                    _ => self.handle_event(Event::NoMatch, ctx),
-                },
+                None => warn!("state `{}` is not set up", state),
+            }
+        }
+
+        self.find_stateless_handler(event, ctx).await
+    }
+
+    /// Finds the handler for an event, regardless of the state of the
+    /// conversation.
+    async fn find_stateless_handler(&self, event: Event, ctx: &mut Context) -> Option<&Handler> {
+        match event {
+            Event::MessageNew => self.find_message_new_handler(ctx).await,
+            Event::MessageEvent => match self.try_find_payload(ctx).await {
+                Some(handler) => handler,
+                None => self.find_event_handler(Event::MessageEvent, ctx).await,
             },
+            e => self.find_event_handler(e, ctx).await,
+        }
+    }
+
+    /// Finds the handler set up via [`Core::on`] for an event, falling back to
+    /// [`Event::NoMatch`] for events related to a conversation.
+    async fn find_event_handler(&self, event: Event, ctx: &Context) -> Option<&Handler> {
+        if let Some(handler) = self.event_handlers.get(&event) {
+            if handler.allows(ctx).await {
+                trace!("calling `{}` handler for {:#?}", event, ctx);
+                return Some(handler);
+            }
+        }
+
+        match event {
+            // Prevent infinite loop when Event::MessageReply handler is not present, while
+            // Event::NoMatch sends a message.
+            Event::MessageReply | Event::NoMatch => None,
+            // Community and unknown events are not necessarily related to a
+            // conversation.
+            e if !e.is_message() => None,
+            _ => self.find_no_match_handler(ctx).await,
+        }
+    }
+
+    /// Finds the [`Event::NoMatch`] handler.
+    async fn find_no_match_handler(&self, ctx: &Context) -> Option<&Handler> {
+        match self.event_handlers.get(&Event::NoMatch) {
+            Some(handler) if handler.allows(ctx).await => Some(handler),
+            _ => None,
+        }
+    }
+
+    /// Finds the handler for the [`Event::MessageNew`], trying to detect
+    /// [`Event::ServiceAction`] first, and then: [`Core::try_find_payload`]
+    /// -> [`Core::try_find_command`] -> [`Core::try_find_regex`] ->
+    /// [`Core::try_find_mounted_no_match`] -> [`Event::NoMatch`].
+    async fn find_message_new_handler(&self, ctx: &mut Context) -> Option<&Handler> {
+        if let Some(action) = ctx.object().action() {
+            let handler = action["type"]
+                .as_str()
+                .and_then(|kind| kind.parse().ok())
+                .and_then(|kind| self.action_handlers.get(&kind));
+            if let Some(handler) = handler {
+                if handler.allows(ctx).await {
+                    trace!("calling `{}` action handler for {:#?}", action["type"], ctx);
+                    return Some(handler);
+                }
+            }
+
+            trace!("calling `service_action` handler for {:#?}", ctx);
+            return self.find_event_handler(Event::ServiceAction, ctx).await;
+        }
+
+        if let Some(handler) = self.try_find_payload(ctx).await {
+            return handler;
+        }
+
+        if let Some(handler) = self.try_find_command(ctx).await {
+            return Some(handler);
+        }
+
+        if let Some(handler) = self.try_find_regex(ctx).await {
+            return Some(handler);
+        }
+
+        if let Some(handler) = self.try_find_mounted_no_match(ctx).await {
+            return Some(handler);
+        }
+
+        trace!(
+            "calling `no_match` (as `message_new` failed to match) handler for {:#?}",
+            ctx
+        );
+        self.find_no_match_handler(ctx).await
+    }
+
+    /// Tries to find a payload handler for this message. Returns `Some` if the
+    /// payload was matched (the inner value is `None` if the matched event has
+    /// no handler), `None` otherwise.
+    async fn try_find_payload(&self, ctx: &mut Context) -> Option<Option<&Handler>> {
+        let payload = ctx.object().payload().clone()?;
+
+        let value = parse_payload(&payload);
+
+        // Handle special payload `{"command": "start"}`
+        if let Some(object) = value.as_object() {
+            if let Some(command) = object.get("command") {
+                if command == "start" {
+                    return Some(self.find_event_handler(Event::Start, ctx).await);
+                }
+            }
+        }
+
+        // Static payload handlers
+        for (expected, handler) in &self.static_payload_handlers {
+            if expected == &value && handler.allows(ctx).await {
+                return Some(Some(handler));
+            }
+        }
+
+        // So-called "dynamic" payload handlers
+        for (tester, handler) in &self.dyn_payload_handlers {
+            if let Some(parsed) = tester.test(&payload) {
+                if handler.allows(ctx).await {
+                    if let Some(parsed) = parsed {
+                        ctx.set_parsed_payload(parsed);
+                    }
+                    return Some(Some(handler));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Tries to find a command handler for this message, and parses the
+    /// arguments of the command. If they do not match, returns a handler
+    /// sending the usage of the command.
+    async fn try_find_command(&self, ctx: &mut Context) -> Option<&Handler> {
+        let text = ctx.object().text().clone()?;
+        let prefix = self.cmd_prefix.as_deref().unwrap_or_default();
+
+        let mut found = None;
+        for (i, end) in self.command_table().find(&text, ctx.group_id()) {
+            if self.commands[i].handler().allows(ctx).await {
+                found = Some((&self.commands[i], end));
+                break;
+            }
+        }
+        let (command, end) = found?;
+
+        if command.is_help() {
+            trace!("replying with help for {:#?}", ctx);
+            ctx.response().set_message(&self.help_message(prefix));
+            return Some(&self.reply_handler);
+        }
+
+        match command.parse(&text[end..]) {
+            Ok(args) => {
+                ctx.set_args(args);
+                Some(command.handler())
+            }
+            Err(e) => {
+                trace!(
+                    "arguments of command `{}` do not match: {}",
+                    command.name(),
+                    e
+                );
+                ctx.response()
+                    .set_message(&format!("{}\nUsage: {}", e, command.usage(prefix)));
+                Some(&self.reply_handler)
+            }
+        }
+    }
+
+    /// Returns the message listing the commands with descriptions.
+    fn help_message(&self, prefix: &str) -> String {
+        let lines: Vec<_> = self
+            .commands
+            .iter()
+            .filter_map(|command| command.help_line(prefix))
+            .collect();
+
+        format!("Commands:\n{}", lines.join("\n"))
+    }
+
+    /// Tries to find a regex handler for this message.
+    async fn try_find_regex(&self, ctx: &mut Context) -> Option<&Handler> {
+        let text = ctx.object().text().clone()?;
+
+        for (i, text) in self.regex_table().matches(&text) {
+            let (_, re, handler) = &self.regex_handlers[i];
+            let captures = match re.captures(text) {
+                Some(captures) => Captures::new(re, &captures),
+                None => continue,
+            };
+            if handler.allows(ctx).await {
+                ctx.set_captures(captures);
+                return Some(handler);
+            }
+        }
+
+        None
+    }
+
+    /// Tries to find the [`Event::NoMatch`] handler of a mounted [`Core`] for
+    /// this message (see [`Core::mount`]).
+    async fn try_find_mounted_no_match(&self, ctx: &Context) -> Option<&Handler> {
+        let text = ctx.object().text().as_deref().unwrap_or_default();
+
+        for (prefix, handler) in &self.mounted_no_match_handlers {
+            if let Some(prefix) = prefix {
+                if strip_mount_prefix(text, prefix).is_none() {
+                    continue;
+                }
+            }
+            if handler.allows(ctx).await {
+                return Some(handler);
+            }
+        }
+
+        None
+    }
+}
+
+/// Regexes compiled for matching, built once per [`Core`]: a [`RegexSet`] for
+/// each prefix of a mounted `Core` (and one for regexes without a prefix).
+#[derive(Debug, Clone)]
+struct RegexTable {
+    groups: Vec<(Option<String>, RegexSet, Vec<usize>)>,
+}
+
+impl RegexTable {
+    /// Compiles the regexes of the given regex handlers.
+    fn new(handlers: &[(Option<String>, Regex, Handler)]) -> Self {
+        let mut groups: Vec<(Option<String>, Vec<&str>, Vec<usize>)> = Vec::new();
+
+        for (i, (prefix, re, _)) in handlers.iter().enumerate() {
+            match groups.iter_mut().find(|(other, _, _)| other == prefix) {
+                Some((_, patterns, indices)) => {
+                    patterns.push(re.as_str());
+                    indices.push(i);
+                }
+                None => groups.push((prefix.clone(), vec![re.as_str()], vec![i])),
+            }
+        }
+
+        Self {
+            groups: groups
+                .into_iter()
+                .map(|(prefix, patterns, indices)| {
+                    (
+                        prefix,
+                        RegexSet::new(patterns).expect("invalid regex"),
+                        indices,
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the indices of the regex handlers matching `text` in the order
+    /// they were set up, along with the text to match (without the prefix).
+    fn matches<'t>(&self, text: &'t str) -> Vec<(usize, &'t str)> {
+        let mut matches = Vec::new();
+
+        for (prefix, set, indices) in &self.groups {
+            let text = match prefix {
+                Some(prefix) => match strip_mount_prefix(text, prefix) {
+                    Some(text) => text,
+                    None => continue,
+                },
+                None => text,
+            };
+            matches.extend(set.matches(text).into_iter().map(|i| (indices[i], text)));
+        }
+
+        matches.sort_unstable_by_key(|&(i, _)| i);
+        matches
+    }
+}
+
+/// Strips the prefix of a mounted [`Core`] (words separated by spaces, each
+/// followed by whitespace or the end of the text) and the whitespace after it.
+fn strip_mount_prefix<'t>(text: &'t str, prefix: &str) -> Option<&'t str> {
+    let mut text = text;
+    for word in prefix.split(' ') {
+        text = text.trim_start().strip_prefix(word)?;
+        if text.starts_with(|c: char| !c.is_whitespace()) {
+            return None;
+        }
+    }
+
+    Some(text.trim_start())
+}
+
+/// Parses a payload as JSON, or returns it as a JSON string if it is not valid
+/// JSON.
+fn parse_payload(payload: &str) -> Value {
+    serde_json::from_str(payload).unwrap_or_else(|_| Value::String(payload.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod event {
+        use super::*;
+
+        fn test_display_parse(expected_str: &str, expected_event: Event) {
+            let event: Event = expected_str
+                .parse()
+                .unwrap_or_else(|_| panic!("could not parse event: `{}`", expected_str));
+            assert_eq!(event, expected_event);
+            let str = format!("{}", event);
+            assert_eq!(str, expected_str);
+        }
+
+        #[test]
+        fn display_and_parse() {
+            test_display_parse("message_new", Event::MessageNew);
+            test_display_parse("message_reply", Event::MessageReply);
+            test_display_parse("message_edit", Event::MessageEdit);
+            test_display_parse("message_typing_state", Event::MessageTypingState);
+            test_display_parse("message_allow", Event::MessageAllow);
+            test_display_parse("message_deny", Event::MessageDeny);
+            test_display_parse("message_event", Event::MessageEvent);
+
+            test_display_parse("wall_post_new", Event::WallPostNew);
+            test_display_parse("wall_reply_new", Event::WallReplyNew);
+            test_display_parse("group_join", Event::GroupJoin);
+            test_display_parse("group_leave", Event::GroupLeave);
+            test_display_parse("photo_new", Event::PhotoNew);
+            test_display_parse("like_add", Event::LikeAdd);
+            test_display_parse("like_remove", Event::LikeRemove);
+            test_display_parse("user_block", Event::UserBlock);
+            test_display_parse("user_unblock", Event::UserUnblock);
+            test_display_parse("poll_vote_new", Event::PollVoteNew);
+            test_display_parse("group_officers_edit", Event::GroupOfficersEdit);
+
+            test_display_parse("start", Event::Start);
+            test_display_parse("service_action", Event::ServiceAction);
+
+            test_display_parse("no_match", Event::NoMatch);
+            test_display_parse("unknown", Event::Unknown);
+        }
+
+        #[test]
+        #[should_panic(expected = "unknown event")]
+        fn unknown() {
+            panic!("{}", "foo_bar".parse::<Event>().unwrap_err());
+        }
+    }
+
+    mod unknown {
+        use super::*;
+        use crate::test_utils::{api, object, request};
+        use std::sync::{mpsc, Mutex};
+
+        #[tokio::test]
+        async fn routed_to_unknown_handler() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Mutex::new(tx);
+
+            let core = Core::new().on(
+                Event::Unknown,
+                Handler::new(move |ctx| {
+                    tx.lock()
+                        .unwrap()
+                        .send((ctx.event(), ctx.event_type().to_string()))
+                        .unwrap();
+                }),
+            );
+
+            core.handle(&request("some_new_event", object(None, None, None)), &api())
+                .await;
+
+            assert_eq!(
+                rx.try_recv().unwrap(),
+                (Event::Unknown, "some_new_event".to_string())
+            );
+        }
+
+        #[tokio::test]
+        async fn ignored_without_handler() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Mutex::new(tx);
+
+            let core = Core::new().on(
+                Event::NoMatch,
+                Handler::new(move |_| tx.lock().unwrap().send(()).unwrap()),
+            );
+
+            core.handle(&request("some_new_event", object(None, None, None)), &api())
+                .await;
+
+            assert!(rx.try_recv().is_err());
+        }
+    }
+
+    mod context_error {
+        use super::*;
+        use crate::{
+            request::Object,
+            test_utils::{api, object, request},
+        };
+        use std::sync::{mpsc, Mutex};
+
+        #[tokio::test]
+        async fn reported_to_handler() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Mutex::new(tx);
+
+            let core = Core::new().on_context_error(ContextErrorHandler::new(move |e, req| {
+                tx.lock()
+                    .unwrap()
+                    .send((e.clone(), req.group_id()))
+                    .unwrap();
+            }));
+
+            core.handle(
+                &request(Event::MessageAllow, object(None, None, None)),
+                &api(),
+            )
+            .await;
+
+            assert_eq!(
+                rx.try_recv().unwrap(),
+                (
+                    ContextError::MissingField {
+                        event: Event::MessageAllow,
+                        field: "user_id"
+                    },
+                    1
+                )
+            );
+        }
+
+        #[tokio::test]
+        async fn peer_less_event() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Mutex::new(tx);
+
+            let core = Core::new().on(
+                Event::MessageDeny,
+                Handler::new(move |ctx| tx.lock().unwrap().send(ctx.peer_id()).unwrap()),
+            );
+
+            core.handle(
+                &request(Event::MessageDeny, object(None, None, None)),
+                &api(),
+            )
+            .await;
+
+            assert_eq!(rx.try_recv().unwrap(), None);
+        }
+
+        #[tokio::test]
+        async fn peer_of_events() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+            let sender = |tx: &Arc<Mutex<mpsc::Sender<_>>>| {
+                let tx = Arc::clone(tx);
+                Handler::new(move |ctx| tx.lock().unwrap().send(ctx.peer_id()).unwrap())
+            };
+
+            let core = Core::new()
+                .on(Event::MessageDeny, sender(&tx))
+                .on(Event::GroupJoin, sender(&tx));
+            let api = api();
+            let user = || Object::new(Some(3), None, Some(2), None, None, None, Default::default());
+
+            core.handle(&request(Event::MessageDeny, user()), &api)
+                .await;
+            core.handle(&request(Event::GroupJoin, user()), &api).await;
+
+            assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![Some(2), None]);
+        }
+    }
+
+    mod command {
+        use super::*;
+        use crate::request::Object;
+
+        fn context(text: &str) -> Context {
+            Context::new(
+                Event::MessageNew,
+                &CallbackAPIRequest::new(
+                    None,
+                    1,
+                    &Event::MessageNew.to_string(),
+                    None,
+                    Object::new(
+                        None,
+                        Some(1),
+                        None,
+                        Some(text.into()),
+                        None,
+                        None,
+                        Default::default(),
+                    ),
+                ),
+                Arc::new(APIClient::new("vk_token")),
+            )
+            .expect("failed to create Context")
+        }
+
+        fn core() -> Core {
+            Core::new()
+                .cmd_prefix("/")
+                .command(
+                    Command::new("ban", Handler::new(|_| {}))
+                        .description("ban a user")
+                        .arg::<i64>("user")
+                        .flag("silent"),
+                )
+                .cmd("echo", Handler::new(|_| {}))
+                .command(
+                    Command::help("help")
+                        .alias("h")
+                        .alias("помощь")
+                        .case_sensitive(false),
+                )
+        }
+
+        #[tokio::test]
+        async fn args() {
+            let core = core();
+            let mut ctx = context("[club1|@bot] /ban 5 --silent");
+            assert!(core
+                .find_handler(Event::MessageNew, &mut ctx)
+                .await
+                .is_some());
+
+            let args = ctx.args().expect("no args");
+            assert_eq!(args.get::<i64>("user"), Some(5));
+            assert!(args.flag("silent"));
+            assert!(ctx.response().message().is_empty());
+        }
+
+        #[tokio::test]
+        async fn rest() {
+            let core = core();
+            let mut ctx = context(r#"/echo hello "big world""#);
+            core.find_handler(Event::MessageNew, &mut ctx).await;
+
+            assert_eq!(ctx.args().unwrap().rest(), ["hello", "big world"]);
+        }
+
+        #[tokio::test]
+        async fn cmd_accepts_any_text() {
+            let core = core();
+            let mut ctx = context("/echo --foo it's 'unclosed");
+            core.find_handler(Event::MessageNew, &mut ctx).await;
+
+            assert_eq!(ctx.args().unwrap().rest(), ["--foo", "it's", "'unclosed"]);
+            assert!(ctx.response().message().is_empty());
+        }
+
+        #[tokio::test]
+        async fn help() {
+            let core = core();
+
+            for text in &["/help", "/Help", "/h", "/ПОМОЩЬ"] {
+                let mut ctx = context(text);
+                core.find_handler(Event::MessageNew, &mut ctx).await;
+
+                assert_eq!(
+                    ctx.response().message(),
+                    "Commands:\n\
+                     /ban <user> [--silent] — ban a user\n\
+                     /help — show this message (aliases: /h, /помощь)"
+                );
+            }
+        }
+
+        #[tokio::test]
+        async fn case_sensitive() {
+            let core = core();
+            let mut ctx = context("/BAN 1");
+            core.find_handler(Event::MessageNew, &mut ctx).await;
+
+            assert!(ctx.args().is_none());
+        }
+
+        #[test]
+        #[should_panic(expected = "duplicate handler for command `h`")]
+        fn duplicate_alias() {
+            core().command(Command::new("H", Handler::new(|_| {})));
+        }
+
+        #[tokio::test]
+        async fn usage() {
+            let core = core();
+            let mut ctx = context("/ban bob");
+            core.find_handler(Event::MessageNew, &mut ctx).await;
+
+            assert!(ctx.args().is_none());
+            assert!(ctx
+                .response()
+                .message()
+                .ends_with("\nUsage: /ban <user> [--silent]"));
+        }
+    }
+
+    mod guard {
+        use super::*;
+        use crate::{guard::CHAT_PEER_ID_OFFSET, test_utils::message_context};
+        use rvk::objects::Integer;
+
+        fn context(peer_id: Integer) -> Context {
+            message_context(peer_id, 2, "/kick 3")
+        }
+
+        fn core() -> Core {
+            Core::new()
+                .cmd_prefix("/")
+                .cmd("kick", Handler::new(|_| {}).guard(Guard::chat()))
+                .regex(
+                    Regex::new(r"^/kick").unwrap(),
+                    Handler::new(|_| {}).guard(Guard::users(vec![3])),
+                )
+                .regex(Regex::new(r"^/(\w+)").unwrap(), Handler::new(|_| {}))
+        }
+
+        #[tokio::test]
+        async fn passed() {
+            let mut ctx = context(CHAT_PEER_ID_OFFSET + 1);
+            assert!(core()
+                .find_handler(Event::MessageNew, &mut ctx)
+                .await
+                .is_some());
+
+            assert_eq!(ctx.args().unwrap().rest(), ["3"]);
+            assert!(ctx.captures().is_none());
+        }
+
+        #[tokio::test]
+        async fn falls_through() {
+            let mut ctx = context(2);
+            assert!(core()
+                .find_handler(Event::MessageNew, &mut ctx)
+                .await
+                .is_some());
+
+            assert!(ctx.args().is_none());
+            assert_eq!(ctx.captures().unwrap().get(1), Some("kick"));
+        }
+
+        #[tokio::test]
+        async fn no_match() {
+            let core = Core::new().on(Event::NoMatch, Handler::new(|_| {}).guard(Guard::chat()));
+
+            assert!(core
+                .find_handler(Event::MessageNew, &mut context(2))
+                .await
+                .is_none());
+            assert!(core
+                .find_handler(Event::MessageNew, &mut context(CHAT_PEER_ID_OFFSET + 1))
+                .await
+                .is_some());
+        }
+
+        #[tokio::test]
+        async fn shorter_command() {
+            let core = Core::new()
+                .cmd_prefix("/")
+                .cmd("ban", Handler::new(|_| {}))
+                .cmd("ban all", Handler::new(|_| {}).guard(Guard::chat()));
+
+            let mut ctx = message_context(2, 2, "/ban all");
+            assert!(core
+                .find_handler(Event::MessageNew, &mut ctx)
+                .await
+                .is_some());
+            assert_eq!(ctx.args().unwrap().rest(), ["all"]);
+
+            let mut ctx = message_context(CHAT_PEER_ID_OFFSET + 1, 2, "/ban all");
+            assert!(core
+                .find_handler(Event::MessageNew, &mut ctx)
+                .await
+                .is_some());
+            assert!(ctx.args().unwrap().rest().is_empty());
+        }
+    }
+
+    mod mount {
+        use super::*;
+        use crate::{
+            guard::CHAT_PEER_ID_OFFSET,
+            test_utils::{api, message},
         };
-    }
+        use rvk::objects::Integer;
+        use std::sync::{mpsc, Mutex};
 
-    /// Handles the [`Event::MessageNew`], trying to detect
-    /// [`Event::ServiceAction`] first, and then: [`Core::try_handle_payload`]
-    /// -> [`Core::try_handle_command`] -> [`Core::try_handle_regex`] ->
-    /// [`Event::NoMatch`].
-    fn handle_message_new(&self, ctx: &mut Context) {
-        if ctx.object().action().is_some() {
-            trace!("calling `service_action` handler for {:#?}", ctx);
-            self.handle_event(Event::ServiceAction, ctx);
-            return;
+        type Sender = Arc<Mutex<mpsc::Sender<&'static str>>>;
+
+        fn report(tx: &Sender, name: &'static str) -> Handler {
+            let tx = Arc::clone(tx);
+            Handler::new(move |_| tx.lock().unwrap().send(name).unwrap())
         }
 
-        if !self.try_handle_payload(ctx)
-            && !self.try_handle_command(ctx)
-            && !self.try_handle_regex(ctx)
-        {
-            trace!(
-                "calling `no_match` (as `message_new` failed to match) handler for {:#?}",
-                ctx
-            );
-            self.handle_event(Event::NoMatch, ctx);
+        async fn handle(core: &Core, peer_id: Integer, text: &str) {
+            core.handle(&message(peer_id, 2, text), &api()).await;
+        }
+
+        fn admin(tx: &Sender) -> Core {
+            let middleware_tx = Arc::clone(tx);
+
+            Core::new()
+                .name("admin")
+                .cmd_prefix("")
+                .cmd("ban", report(tx, "admin ban"))
+                .regex(Regex::new(r"^say (\w+)$").unwrap(), report(tx, "admin say"))
+                .on(Event::NoMatch, report(tx, "admin no_match"))
+                .wrap(Middleware::new(move |ctx, next| {
+                    middleware_tx
+                        .lock()
+                        .unwrap()
+                        .send("admin middleware")
+                        .unwrap();
+                    next.run(ctx)
+                }))
+        }
+
+        #[tokio::test]
+        async fn prefix() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+
+            let core = Core::new()
+                .cmd_prefix("/")
+                .cmd("ban", report(&tx, "ban"))
+                .on(Event::NoMatch, report(&tx, "no_match"))
+                .mount("/admin", admin(&tx));
+
+            for (text, expected) in &[
+                ("/ban 1", &["ban"][..]),
+                ("/admin ban 1", &["admin middleware", "admin ban"]),
+                ("/admin  say hi", &["admin middleware", "admin say"]),
+                ("/admin what", &["admin middleware", "admin no_match"]),
+                ("/administrator", &["no_match"]),
+            ] {
+                handle(&core, 2, text).await;
+                assert_eq!(rx.try_iter().collect::<Vec<_>>(), *expected, "{}", text);
+            }
+        }
+
+        #[tokio::test]
+        async fn guard() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+
+            let core = Core::new()
+                .cmd_prefix("/")
+                .on(Event::NoMatch, report(&tx, "no_match"))
+                .mount(
+                    Guard::chat(),
+                    Core::new()
+                        .cmd_prefix("/")
+                        .cmd("poll", report(&tx, "chat poll"))
+                        .on(Event::NoMatch, report(&tx, "chat no_match")),
+                )
+                .mount(
+                    Guard::private(),
+                    Core::new().cmd("start", report(&tx, "private start")),
+                );
+
+            for (peer_id, text, expected) in &[
+                (2, "/poll", "no_match"),
+                (CHAT_PEER_ID_OFFSET + 1, "/poll", "chat poll"),
+                (CHAT_PEER_ID_OFFSET + 1, "hello", "chat no_match"),
+                (2, "/start", "private start"),
+                (2, "start", "no_match"),
+            ] {
+                handle(&core, *peer_id, text).await;
+                assert_eq!(rx.try_iter().collect::<Vec<_>>(), [*expected], "{}", text);
+            }
+        }
+
+        #[tokio::test]
+        async fn no_match_after_regexes() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+
+            let core = Core::new()
+                .mount(
+                    Guard::chat(),
+                    Core::new()
+                        .mount("admin", admin(&tx))
+                        .on(Event::NoMatch, report(&tx, "chat no_match")),
+                )
+                .regex(Regex::new("^hello").unwrap(), report(&tx, "hello"));
+
+            for (text, expected) in &[
+                ("hello", &["hello"][..]),
+                ("admin hello", &["admin middleware", "admin no_match"]),
+                ("bye", &["chat no_match"]),
+            ] {
+                handle(&core, CHAT_PEER_ID_OFFSET + 1, text).await;
+                assert_eq!(rx.try_iter().collect::<Vec<_>>(), *expected, "{}", text);
+            }
+        }
+
+        #[test]
+        #[should_panic(expected = "duplicate handler for command `ban` (in module `admin`)")]
+        fn duplicate() {
+            let (tx, _) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+
+            Core::new()
+                .mount("/admin", admin(&tx))
+                .mount("/admin", admin(&tx));
         }
     }
 
-    /// Tries to handle this message using a payload handler. Returns `true` if
-    /// that was successful, `false` otherwise.
-    fn try_handle_payload(&self, ctx: &mut Context) -> bool {
-        let payload = match ctx.object().payload() {
-            Some(payload) => payload,
-            None => return false,
+    mod state {
+        use super::*;
+        use crate::{
+            guard::CHAT_PEER_ID_OFFSET,
+            test_utils::{api, message},
         };
+        use rvk::objects::Integer;
+        use std::sync::{mpsc, Mutex};
 
-        // Handle special payload `{"command": "start"}`
-        if let Ok(payload) = serde_json::from_str::<Value>(payload) {
-            if let Some(object) = payload.as_object() {
-                if let Some(command) = object.get("command") {
-                    if command == "start" {
-                        self.handle_event(Event::Start, ctx);
-                        return true;
-                    }
-                }
+        type Sender = Arc<Mutex<mpsc::Sender<&'static str>>>;
+
+        fn report(tx: &Sender, name: &'static str) -> Handler {
+            let tx = Arc::clone(tx);
+            Handler::new(move |_| tx.lock().unwrap().send(name).unwrap())
+        }
+
+        async fn handle(core: &Core, text: &str) {
+            handle_from(core, 2, 2, text).await;
+        }
+
+        async fn handle_from(core: &Core, peer_id: Integer, from_id: Integer, text: &str) {
+            core.handle(&message(peer_id, from_id, text), &api()).await;
+        }
+
+        fn core(tx: &Sender, timeout: Duration) -> Core {
+            let email_tx = Arc::clone(tx);
+
+            Core::new()
+                .cmd_prefix("/")
+                .cmd(
+                    "register",
+                    Handler::new(|ctx| ctx.set_state("awaiting_email")),
+                )
+                .on(Event::NoMatch, report(tx, "no_match"))
+                .state(
+                    "awaiting_email",
+                    Core::new()
+                        .on(
+                            Event::NoMatch,
+                            Handler::new(move |ctx| {
+                                ctx.clear_state();
+                                email_tx.lock().unwrap().send("email").unwrap();
+                            }),
+                        )
+                        .state_timeout(timeout, report(tx, "timeout")),
+                )
+        }
+
+        #[tokio::test]
+        async fn routed_first() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+            let core = core(&tx, Duration::from_secs(60));
+
+            for text in &["hello", "/register", "me@example.com", "hello"] {
+                handle(&core, text).await;
             }
+
+            assert_eq!(
+                rx.try_iter().collect::<Vec<_>>(),
+                ["no_match", "email", "no_match"]
+            );
         }
 
-        // Static payload handlers
-        if let Some(handler) = self.static_payload_handlers.get(payload) {
-            handler(ctx);
-            return true;
+        #[tokio::test]
+        async fn scope() {
+            let chat = CHAT_PEER_ID_OFFSET + 1;
+
+            for (scope, expected) in &[
+                (SessionScope::UserInChat, ["no_match", "email"]),
+                (SessionScope::Chat, ["email", "no_match"]),
+            ] {
+                let (tx, rx) = mpsc::channel();
+                let tx = Arc::new(Mutex::new(tx));
+                let core = core(&tx, Duration::from_secs(60)).state_scope(*scope);
+
+                handle_from(&core, chat, 2, "/register").await;
+                handle_from(&core, chat, 3, "hello").await;
+                handle_from(&core, chat, 2, "me@example.com").await;
+
+                assert_eq!(rx.try_iter().collect::<Vec<_>>(), *expected, "{:?}", scope);
+            }
         }
 
-        // So-called "dynamic" payload handlers
-        for (tester, handler) in &self.dyn_payload_handlers {
-            if tester(payload) {
-                handler(ctx);
-                return true;
+        #[tokio::test]
+        async fn command_prefix_inherited() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+            let cancel_tx = Arc::clone(&tx);
+
+            let core = Core::new()
+                .cmd(
+                    "register",
+                    Handler::new(|ctx| ctx.set_state("awaiting_email")),
+                )
+                .state(
+                    "awaiting_email",
+                    Core::new()
+                        .cmd(
+                            "cancel",
+                            Handler::new(move |ctx| {
+                                ctx.clear_state();
+                                cancel_tx.lock().unwrap().send("cancel").unwrap();
+                            }),
+                        )
+                        .on(Event::NoMatch, report(&tx, "email")),
+                )
+                .cmd_prefix("/");
+
+            for text in &["/register", "cancel", "/cancel", "/cancel"] {
+                handle(&core, text).await;
             }
+
+            assert_eq!(rx.try_iter().collect::<Vec<_>>(), ["email", "cancel"]);
         }
 
-        false
-    }
+        #[tokio::test]
+        async fn timeout() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+            let core = core(&tx, Duration::from_millis(50));
 
-    /// Tries to handle this message using a command handler. Returns `true` if
-    /// that was successful, `false` otherwise.
-    fn try_handle_command(&self, ctx: &mut Context) -> bool {
-        if let Some(text) = ctx.object().text() {
-            for command in self.command_handlers.keys() {
-                use regex::escape;
+            handle(&core, "/register").await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            handle(&core, "me@example.com").await;
 
-                let re = Regex::new(&format!(
-                    r#"^( *\[club{}\|.*\])?( *{}{})+"#,
-                    ctx.group_id(),
-                    match &self.cmd_prefix {
-                        Some(prefix) => escape(prefix.as_str()),
-                        None => "".into(),
-                    },
-                    escape(command)
-                ))
-                .expect("invalid regex");
+            assert_eq!(rx.try_iter().collect::<Vec<_>>(), ["timeout", "no_match"]);
+        }
 
-                if re.is_match(&text) {
-                    self.command_handlers[command](ctx);
-                    return true;
-                }
+        #[tokio::test]
+        async fn timeout_cancelled() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+            let core = core(&tx, Duration::from_secs(60));
+            let (tasks, stop) = (TaskTracker::new(), CancellationToken::new());
+            core.attach(&tasks, &stop);
+
+            for text in &["/register", "me@example.com", "/register"] {
+                handle(&core, text).await;
             }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(tasks.len(), 1);
+
+            stop.cancel();
+            tasks.close();
+            tokio::time::timeout(Duration::from_millis(10), tasks.wait())
+                .await
+                .unwrap();
+
+            assert_eq!(rx.try_iter().collect::<Vec<_>>(), ["email"]);
+        }
+
+        #[tokio::test]
+        async fn timeout_with_middleware_and_error_handler() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+            let (middleware_tx, error_tx) = (Arc::clone(&tx), Arc::clone(&tx));
+
+            let core = Core::new()
+                .cmd_prefix("/")
+                .cmd(
+                    "register",
+                    Handler::new(|ctx| ctx.set_state("awaiting_email")),
+                )
+                .state(
+                    "awaiting_email",
+                    Core::new().state_timeout(
+                        Duration::from_millis(50),
+                        Handler::new(|_| Err("timed out")),
+                    ),
+                )
+                .wrap(Middleware::new(move |ctx, next| {
+                    middleware_tx.lock().unwrap().send("middleware").unwrap();
+                    next.run(ctx)
+                }))
+                .on_error(ErrorHandler::new(move |e, _| {
+                    assert_eq!(e.to_string(), "timed out");
+                    error_tx.lock().unwrap().send("error").unwrap();
+                }));
+
+            handle(&core, "/register").await;
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            assert_eq!(
+                rx.try_iter().collect::<Vec<_>>(),
+                ["middleware", "middleware", "error"]
+            );
         }
 
-        false
+        #[test]
+        #[should_panic(expected = "duplicate state `a`")]
+        fn duplicate() {
+            Core::new()
+                .state("a", Core::new())
+                .state("b", Core::new().state("a", Core::new()));
+        }
     }
 
-    /// Tries to handle this message using a regex handler. Returns `true` if
-    /// that was successful, `false` otherwise.
-    fn try_handle_regex(&self, ctx: &mut Context) -> bool {
-        if let Some(text) = ctx.object().text() {
-            for (re, handler) in self.regex_handlers.iter() {
-                if re.is_match(&text) {
-                    handler(ctx);
-                    return true;
-                }
-            }
+    mod regex {
+        use super::*;
+        use crate::request::Object;
+
+        #[tokio::test]
+        async fn captures() {
+            let core = Core::new()
+                .regex(Regex::new(r"^hi (\w+)").unwrap(), Handler::new(|_| {}))
+                .regex(
+                    Regex::new(r"(?P<count>\d+) (?P<item>[a-z]+?)(s)?( please)?$").unwrap(),
+                    Handler::new(|_| {}),
+                );
+
+            let mut ctx = Context::new(
+                Event::MessageNew,
+                &CallbackAPIRequest::new(
+                    None,
+                    1,
+                    &Event::MessageNew.to_string(),
+                    None,
+                    Object::new(
+                        None,
+                        Some(1),
+                        None,
+                        Some("buy 3 apples".into()),
+                        None,
+                        None,
+                        Default::default(),
+                    ),
+                ),
+                Arc::new(APIClient::new("vk_token")),
+            )
+            .expect("failed to create Context");
+
+            assert!(core
+                .find_handler(Event::MessageNew, &mut ctx)
+                .await
+                .is_some());
+
+            let captures = ctx.captures().expect("no captures");
+            assert_eq!(captures.len(), 5);
+            assert_eq!(captures.get(0), Some("3 apples"));
+            assert_eq!(captures.get(1), Some("3"));
+            assert_eq!(captures.get(3), Some("s"));
+            assert_eq!(captures.get(4), None);
+            assert_eq!(ctx.capture("count"), Some("3"));
+            assert_eq!(ctx.capture("item"), Some("apple"));
+            assert_eq!(ctx.capture("missing"), None);
+        }
+
+        #[test]
+        fn order() {
+            let handler = || Handler::new(|_| {});
+            let core = Core::new()
+                .regex(Regex::new("a").unwrap(), handler())
+                .mount(
+                    "x",
+                    Core::new()
+                        .regex(Regex::new("b").unwrap(), handler())
+                        .regex(Regex::new("^a").unwrap(), handler()),
+                )
+                .regex(Regex::new("b").unwrap(), handler());
+            let matches = |text| {
+                core.regex_table()
+                    .matches(text)
+                    .into_iter()
+                    .map(|(i, text)| (i, text.to_string()))
+                    .collect::<Vec<_>>()
+            };
+
+            assert_eq!(
+                matches("x ab"),
+                [
+                    (0, "x ab".into()),
+                    (1, "ab".into()),
+                    (2, "ab".into()),
+                    (3, "x ab".into())
+                ]
+            );
+            assert_eq!(matches("b"), [(3, "b".into())]);
         }
 
-        false
+        #[test]
+        fn build() {
+            let core = Core::new()
+                .cmd("a", Handler::new(|_| {}))
+                .state(
+                    "s",
+                    Core::new().regex(Regex::new("b").unwrap(), Handler::new(|_| {})),
+                )
+                .build();
+
+            assert!(core.command_table.get().is_some());
+            assert!(core.regex_table.get().is_some());
+            assert!(core.states["s"].regex_table.get().is_some());
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    mod payload {
+        use super::*;
+        use crate::request::Object;
+        use serde_derive::Deserialize;
+        use std::sync::{mpsc, Mutex};
 
-    mod event {
+        #[derive(Deserialize)]
+        struct Buy {
+            item: u32,
+        }
+
+        async fn handle(payload: &str) -> String {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+            let sender = |name: &'static str| {
+                let tx = Arc::clone(&tx);
+                Handler::new(move |_| tx.lock().unwrap().send(name.to_string()).unwrap())
+            };
+            let typed_tx = Arc::clone(&tx);
+
+            let core = Core::new()
+                .payload(r#"{"a":"b","c":[1,2]}"#, sender("payload"))
+                .payload("plain", sender("plain"))
+                .typed_payload(move |_, buy: Buy| {
+                    let tx = Arc::clone(&typed_tx);
+                    async move {
+                        tx.lock()
+                            .unwrap()
+                            .send(format!("buy {}", buy.item))
+                            .unwrap()
+                    }
+                })
+                .on(Event::NoMatch, sender("no_match"));
+
+            core.handle(
+                &CallbackAPIRequest::new(
+                    None,
+                    1,
+                    &Event::MessageNew.to_string(),
+                    None,
+                    Object::new(
+                        None,
+                        Some(1),
+                        None,
+                        None,
+                        Some(payload.into()),
+                        None,
+                        Default::default(),
+                    ),
+                ),
+                &Arc::new(APIClient::new("vk_token")),
+            )
+            .await;
+
+            rx.try_recv().unwrap()
+        }
+
+        #[tokio::test]
+        async fn structural() {
+            assert_eq!(handle(r#"{"a":"b","c":[1,2]}"#).await, "payload");
+            assert_eq!(handle(r#"{ "c": [1, 2], "a": "b" }"#).await, "payload");
+            assert_eq!(handle(r#"{"a":"b","c":[2,1]}"#).await, "no_match");
+            assert_eq!(handle("plain").await, "plain");
+        }
+
+        #[tokio::test]
+        async fn typed() {
+            assert_eq!(handle(r#"{"item": 5}"#).await, "buy 5");
+            assert_eq!(handle(r#"{"item": "five"}"#).await, "no_match");
+        }
+
+        #[test]
+        #[should_panic(expected = "duplicate handler for payload")]
+        fn duplicate() {
+            Core::new()
+                .payload(r#"{"a": 1, "b": 2}"#, Handler::new(|_| {}))
+                .payload(r#"{"b":2,"a":1}"#, Handler::new(|_| {}));
+        }
+    }
+
+    mod action {
         use super::*;
+        use std::sync::{mpsc, Mutex};
 
-        fn test_display_parse(expected_str: &str, expected_event: Event) {
-            let event: Event = expected_str
-                .parse()
-                .expect(&format!("could not parse event: `{}`", expected_str));
-            assert_eq!(event, expected_event);
-            let str = format!("{}", event);
-            assert_eq!(str, expected_str);
+        async fn handle(action: &str) -> &'static str {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+            let sender = |name| {
+                let tx = Arc::clone(&tx);
+                Handler::new(move |_| tx.lock().unwrap().send(name).unwrap())
+            };
+
+            let core = Core::new()
+                .on_action(ActionKind::ChatInviteUser, sender("invite"))
+                .on_action(ActionKind::ChatKickUser, sender("kick"))
+                .on(Event::ServiceAction, sender("service_action"));
+
+            let req: CallbackAPIRequest = serde_json::from_str(&format!(
+                r#"{{"type": "message_new", "group_id": 1, "object": {{
+                    "date": 100, "peer_id": 2000000001, "from_id": 2, "action": {}
+                }}}}"#,
+                action
+            ))
+            .unwrap();
+
+            core.handle(&req, &Arc::new(APIClient::new("vk_token")))
+                .await;
+
+            rx.try_recv().unwrap()
+        }
+
+        #[tokio::test]
+        async fn routed_by_kind() {
+            assert_eq!(
+                handle(r#"{"type": "chat_invite_user", "member_id": 3}"#).await,
+                "invite"
+            );
+            assert_eq!(
+                handle(r#"{"type": "chat_kick_user", "member_id": 3}"#).await,
+                "kick"
+            );
+        }
+
+        #[tokio::test]
+        async fn fallback() {
+            assert_eq!(
+                handle(r#"{"type": "chat_title_update", "text": "title"}"#).await,
+                "service_action"
+            );
+            assert_eq!(
+                handle(r#"{"type": "chat_screenshot"}"#).await,
+                "service_action"
+            );
         }
 
         #[test]
-        fn display_and_parse() {
-            test_display_parse("message_new", Event::MessageNew);
-            test_display_parse("message_reply", Event::MessageReply);
-            test_display_parse("message_edit", Event::MessageEdit);
-            test_display_parse("message_typing_state", Event::MessageTypingState);
-            test_display_parse("message_allow", Event::MessageAllow);
-            test_display_parse("message_deny", Event::MessageDeny);
+        #[should_panic(expected = "duplicate handler for action `chat_create`")]
+        fn duplicate() {
+            Core::new()
+                .on_action(ActionKind::ChatCreate, Handler::new(|_| {}))
+                .on_action(ActionKind::ChatCreate, Handler::new(|_| {}));
+        }
+    }
 
-            test_display_parse("start", Event::Start);
-            test_display_parse("service_action", Event::ServiceAction);
+    mod message_event {
+        use super::*;
+        use std::sync::{mpsc, Mutex};
 
-            test_display_parse("no_match", Event::NoMatch);
+        async fn handle(payload: &str) -> &'static str {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+            let sender = |name| {
+                let tx = Arc::clone(&tx);
+                Handler::new(move |_| tx.lock().unwrap().send(name).unwrap())
+            };
+
+            let core = Core::new()
+                .payload(r#"{"a":"b"}"#, sender("payload"))
+                .dyn_payload(Tester::new(|p| p.contains('c')), sender("dyn_payload"))
+                .cmd("c", sender("command"))
+                .on(Event::MessageEvent, sender("message_event"))
+                .on(Event::NoMatch, sender("no_match"));
+
+            let req: CallbackAPIRequest = serde_json::from_str(&format!(
+                r#"{{"type": "message_event", "group_id": 1, "object": {{
+                    "user_id": 2, "peer_id": 2, "event_id": "abc", "payload": {}
+                }}}}"#,
+                payload
+            ))
+            .unwrap();
+
+            core.handle(&req, &Arc::new(APIClient::new("vk_token")))
+                .await;
+
+            rx.try_recv().unwrap()
+        }
+
+        #[tokio::test]
+        async fn payload() {
+            assert_eq!(handle(r#"{"a": "b"}"#).await, "payload");
+        }
+
+        #[tokio::test]
+        async fn dyn_payload() {
+            assert_eq!(handle(r#"{"c": 1}"#).await, "dyn_payload");
+        }
+
+        #[tokio::test]
+        async fn fallback() {
+            assert_eq!(handle(r#"{"d": 1}"#).await, "message_event");
+        }
+    }
+
+    mod handler_error {
+        use super::*;
+        use crate::test_utils::{api, object, request};
+        use std::{
+            fmt,
+            sync::{mpsc, Mutex},
+        };
+
+        #[derive(Debug, PartialEq)]
+        struct TestError;
+
+        impl Display for TestError {
+            fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+                f.write_str("test error")
+            }
+        }
+
+        async fn handle(core: Core) {
+            core.handle(
+                &request(Event::MessageDeny, object(None, Some(2), None)),
+                &api(),
+            )
+            .await;
         }
 
         #[test]
-        #[should_panic(expected = "unknown event")]
-        fn unknown() {
-            panic!("{}", "foo_bar".parse::<Event>().unwrap_err());
+        fn new_does_not_rewrap() {
+            let e = HandlerError::new(HandlerError::new(TestError));
+            assert_eq!(e.downcast_ref::<TestError>(), Some(&TestError));
+            assert_eq!(e.to_string(), "test error");
+        }
+
+        #[tokio::test]
+        async fn routed_to_error_handler() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Mutex::new(tx);
+
+            handle(
+                Core::new()
+                    .on(Event::MessageDeny, Handler::new(|_| Err(TestError)))
+                    .on_error(ErrorHandler::new(move |e, ctx| {
+                        tx.lock()
+                            .unwrap()
+                            .send((e.downcast_ref::<TestError>().is_some(), ctx.peer_id()))
+                            .unwrap();
+                    })),
+            )
+            .await;
+
+            assert_eq!(rx.try_recv().unwrap(), (true, Some(2)));
+        }
+
+        #[tokio::test]
+        async fn async_routed_to_error_handler() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Mutex::new(tx);
+
+            handle(
+                Core::new()
+                    .on(
+                        Event::MessageDeny,
+                        Handler::new_async(|_| async { Err(TestError) }),
+                    )
+                    .on_error(ErrorHandler::new_async(move |e, _| {
+                        let tx = tx.lock().unwrap().clone();
+                        async move { tx.send(e.to_string()).unwrap() }
+                    })),
+            )
+            .await;
+
+            assert_eq!(rx.try_recv().unwrap(), "test error");
+        }
+
+        #[tokio::test]
+        async fn not_called_on_success() {
+            handle(
+                Core::new()
+                    .on(Event::MessageDeny, Handler::new(|_| Ok::<_, TestError>(())))
+                    .on_error(ErrorHandler::new(|_, _| panic!("error handler called"))),
+            )
+            .await;
+        }
+    }
+
+    mod middleware {
+        use super::*;
+        use crate::test_utils::{api, message};
+        use std::sync::{mpsc, Mutex};
+
+        /// Returns a [`Middleware`] which reports `name` before and after the
+        /// rest of the chain, or stops it if `stop` is set.
+        fn middleware(
+            tx: &Arc<Mutex<mpsc::Sender<String>>>,
+            name: &'static str,
+            stop: bool,
+        ) -> Middleware {
+            let tx = Arc::clone(tx);
+            Middleware::new(move |ctx, next| {
+                let tx = tx.lock().unwrap().clone();
+                Box::pin(async move {
+                    tx.send(format!("{} before", name)).unwrap();
+                    if stop {
+                        return Ok(());
+                    }
+                    ctx.response().set_message(name);
+                    let result = next.run(ctx).await;
+                    tx.send(format!("{} after", name)).unwrap();
+                    result
+                })
+            })
+        }
+
+        async fn handle(core: Core, rx: mpsc::Receiver<String>) -> Vec<String> {
+            core.handle(&message(2, 2, "/test"), &api()).await;
+
+            rx.try_iter().collect()
+        }
+
+        fn core(tx: &Arc<Mutex<mpsc::Sender<String>>>) -> Core {
+            let handler_tx = Arc::clone(tx);
+            Core::new().cmd_prefix("/").cmd(
+                "test",
+                Handler::new(move |ctx| {
+                    handler_tx
+                        .lock()
+                        .unwrap()
+                        .send(format!("handler {}", ctx.response().message()))
+                        .unwrap();
+                }),
+            )
+        }
+
+        #[tokio::test]
+        async fn order() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+
+            let core = core(&tx)
+                .wrap(middleware(&tx, "a", false))
+                .wrap(middleware(&tx, "b", false));
+
+            assert_eq!(
+                handle(core, rx).await,
+                ["a before", "b before", "handler b", "b after", "a after"]
+            );
+        }
+
+        #[tokio::test]
+        async fn short_circuit() {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(Mutex::new(tx));
+
+            let core = core(&tx)
+                .wrap(middleware(&tx, "a", true))
+                .wrap(middleware(&tx, "b", false));
+
+            assert_eq!(handle(core, rx).await, ["a before"]);
         }
     }
 
@@ -506,11 +2959,11 @@ mod tests {
             })
         }
 
-        fn test_wiring(obj: Object) -> Wiring {
+        async fn test_wiring(obj: Object) -> Wiring {
             let (tx, rx) = mpsc::sync_channel(1);
             let tx = Arc::new(Mutex::new(tx));
 
-            let api = APIClient::new("vk_token");
+            let api = Arc::new(APIClient::new("vk_token"));
 
             let mut ctx = Context::new(
                 Event::MessageNew,
@@ -518,10 +2971,12 @@ mod tests {
                     Some("secret".into()),
                     1,
                     &Event::MessageNew.to_string(),
+                    None,
                     obj,
                 ),
-                &api,
-            );
+                api,
+            )
+            .expect("failed to create Context");
 
             Core::new()
                 .cmd_prefix("/")
@@ -541,13 +2996,14 @@ mod tests {
                     wiring_sender(&tx, Wiring::Regex),
                 )
                 .on(Event::NoMatch, wiring_sender(&tx, Wiring::NoMatch))
-                .handle_event(Event::MessageNew, &mut ctx);
+                .handle_event(Event::MessageNew, &mut ctx)
+                .await;
 
             rx.recv().expect("failed to recv Wiring")
         }
 
-        #[test]
-        fn service_action() {
+        #[tokio::test]
+        async fn service_action() {
             assert_eq!(
                 test_wiring(Object::new(
                     None,               // from_id
@@ -557,13 +3013,14 @@ mod tests {
                     None,               // payload
                     Some(Value::Null),  // action
                     Default::default()  // extra fields
-                )),
+                ))
+                .await,
                 Wiring::ServiceAction
             );
         }
 
-        #[test]
-        fn start() {
+        #[tokio::test]
+        async fn start() {
             assert_eq!(
                 test_wiring(Object::new(
                     None,                                   // from_id
@@ -573,13 +3030,14 @@ mod tests {
                     Some(r#"{"command": "start"}"#.into()), // payload
                     None,                                   // action
                     Default::default()                      // extra fields
-                )),
+                ))
+                .await,
                 Wiring::Start
             );
         }
 
-        #[test]
-        fn payload() {
+        #[tokio::test]
+        async fn payload() {
             assert_eq!(
                 test_wiring(Object::new(
                     None,                         // from_id
@@ -589,13 +3047,14 @@ mod tests {
                     Some(r#"{"a": "b"}"#.into()), // payload
                     None,                         // action
                     Default::default()            // extra fields
-                )),
+                ))
+                .await,
                 Wiring::Payload
             );
         }
 
-        #[test]
-        fn dyn_payload() {
+        #[tokio::test]
+        async fn dyn_payload() {
             assert_eq!(
                 test_wiring(Object::new(
                     None,                                   // from_id
@@ -605,13 +3064,14 @@ mod tests {
                     Some(r#"{"other": "payload"}"#.into()), // payload
                     None,                                   // action
                     Default::default()                      // extra fields
-                )),
+                ))
+                .await,
                 Wiring::DynPayload
             );
         }
 
-        #[test]
-        fn command() {
+        #[tokio::test]
+        async fn command() {
             assert_eq!(
                 test_wiring(Object::new(
                     None,                                    // from_id
@@ -621,13 +3081,14 @@ mod tests {
                     None,                                    // payload
                     None,                                    // action
                     Default::default()                       // extra fields
-                )),
+                ))
+                .await,
                 Wiring::Command
             );
         }
 
-        #[test]
-        fn regex() {
+        #[tokio::test]
+        async fn regex() {
             assert_eq!(
                 test_wiring(Object::new(
                     None,                // from_id
@@ -637,13 +3098,14 @@ mod tests {
                     None,                // payload
                     None,                // action
                     Default::default()   // extra fields
-                )),
+                ))
+                .await,
                 Wiring::Regex
             );
         }
 
-        #[test]
-        fn no_match() {
+        #[tokio::test]
+        async fn no_match() {
             assert_eq!(
                 test_wiring(Object::new(
                     None,                 // from_id
@@ -653,7 +3115,8 @@ mod tests {
                     None,                 // payload
                     None,                 // action
                     Default::default()    // extra fields
-                )),
+                ))
+                .await,
                 Wiring::NoMatch
             );
         }
@@ -0,0 +1,190 @@
+//! Deduplication of repeated Callback API deliveries.
+//!
+//! VK delivers an event again if it does not receive `ok` in time. Every
+//! delivery of the same event has the same `event_id`, so repeated deliveries
+//! can be dropped before any handler runs. See [`BotBuilder::dedup`].
+//!
+//! [`BotBuilder::dedup`]: crate::bot::BotBuilder::dedup
+
+use crate::request::CallbackAPIRequest;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Debug, Error, Formatter},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Storage for event IDs that have already been seen.
+pub trait DedupStore: Send + Sync {
+    /// Records `key` as seen, and returns `true` if it was not seen within the
+    /// last `window`.
+    fn insert(&self, key: &str, window: Duration) -> bool;
+
+    /// Forgets `key`, so that it is considered new again.
+    fn remove(&self, key: &str);
+}
+
+/// In-memory [`DedupStore`], which forgets keys once they are older than the
+/// window.
+#[derive(Debug, Default)]
+pub struct MemoryDedupStore {
+    inner: Mutex<MemoryDedupStoreInner>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryDedupStoreInner {
+    seen: HashMap<String, Instant>,
+    order: VecDeque<(Instant, String)>,
+}
+
+impl MemoryDedupStore {
+    /// Creates a new [`MemoryDedupStore`].
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl DedupStore for MemoryDedupStore {
+    fn insert(&self, key: &str, window: Duration) -> bool {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().expect("failed to lock Mutex");
+
+        while let Some((seen_at, _)) = inner.order.front() {
+            if now.duration_since(*seen_at) < window {
+                break;
+            }
+
+            let (seen_at, key) = inner.order.pop_front().unwrap();
+            // The key may have been seen again later, keep it in that case.
+            if inner.seen.get(&key) == Some(&seen_at) {
+                inner.seen.remove(&key);
+            }
+        }
+
+        if inner.seen.contains_key(key) {
+            return false;
+        }
+
+        inner.seen.insert(key.into(), now);
+        inner.order.push_back((now, key.into()));
+        true
+    }
+
+    fn remove(&self, key: &str) {
+        // The entry in `order` is skipped once it expires, as it no longer
+        // matches `seen`.
+        self.inner
+            .lock()
+            .expect("failed to lock Mutex")
+            .seen
+            .remove(key);
+    }
+}
+
+/// Drops requests whose `event_id` was already seen within a time window.
+#[derive(Clone)]
+pub struct Dedup {
+    store: Arc<dyn DedupStore>,
+    window: Duration,
+}
+
+impl Dedup {
+    /// Creates a new [`Dedup`] using the given store and time window.
+    pub fn new<S>(store: S, window: Duration) -> Self
+    where
+        S: DedupStore + 'static,
+    {
+        Self {
+            store: Arc::new(store),
+            window,
+        }
+    }
+
+    /// Returns the time window.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Returns `true` if this request is delivered for the first time within
+    /// the window. Requests without an `event_id` are always considered new.
+    pub fn is_new(&self, req: &CallbackAPIRequest) -> bool {
+        match req.event_id() {
+            Some(event_id) => self
+                .store
+                .insert(&Self::key(req.group_id(), event_id), self.window),
+            None => true,
+        }
+    }
+
+    /// Forgets that the event with the given ID was seen, so that a repeated
+    /// delivery of it is considered new. Used when the event could not be
+    /// handled.
+    pub fn forget(&self, group_id: i32, event_id: &str) {
+        self.store.remove(&Self::key(group_id, event_id));
+    }
+
+    /// Returns the key identifying an event in the store.
+    fn key(group_id: i32, event_id: &str) -> String {
+        format!("{}:{}", group_id, event_id)
+    }
+}
+
+impl Debug for Dedup {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "Dedup {{ window: {:?}, ... }}", self.window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn request(group_id: i32, event_id: Option<&str>) -> CallbackAPIRequest {
+        CallbackAPIRequest::new(
+            None,
+            group_id,
+            "message_new",
+            event_id.map(Into::into),
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn drops_repeated_event_ids() {
+        let dedup = Dedup::new(MemoryDedupStore::new(), Duration::from_secs(60));
+
+        assert!(dedup.is_new(&request(1, Some("a"))));
+        assert!(!dedup.is_new(&request(1, Some("a"))));
+        assert!(dedup.is_new(&request(1, Some("b"))));
+        assert!(dedup.is_new(&request(2, Some("a"))));
+    }
+
+    #[test]
+    fn keeps_requests_without_event_id() {
+        let dedup = Dedup::new(MemoryDedupStore::new(), Duration::from_secs(60));
+
+        assert!(dedup.is_new(&request(1, None)));
+        assert!(dedup.is_new(&request(1, None)));
+    }
+
+    #[test]
+    fn forget() {
+        let dedup = Dedup::new(MemoryDedupStore::new(), Duration::from_secs(60));
+
+        assert!(dedup.is_new(&request(1, Some("a"))));
+        dedup.forget(1, "a");
+        assert!(dedup.is_new(&request(1, Some("a"))));
+        assert!(!dedup.is_new(&request(1, Some("a"))));
+    }
+
+    #[test]
+    fn forgets_after_window() {
+        let dedup = Dedup::new(MemoryDedupStore::new(), Duration::from_millis(50));
+
+        assert!(dedup.is_new(&request(1, Some("a"))));
+        thread::sleep(Duration::from_millis(100));
+        assert!(dedup.is_new(&request(1, Some("a"))));
+        assert!(!dedup.is_new(&request(1, Some("a"))));
+    }
+}
@@ -0,0 +1,311 @@
+//! Guards restricting which events a [`Handler`](crate::Handler) handles,
+//! see [`Handler::guard`](crate::Handler::guard).
+
+use crate::{api, context::Context};
+use rvk::{objects::Integer, Params};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Error, Formatter},
+    future::{self, Future},
+    ops::{Deref, Not},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Peer IDs of group chats are greater than this value.
+pub const CHAT_PEER_ID_OFFSET: Integer = 2_000_000_000;
+
+/// How long [`Guard::managers`] keeps the managers of a community.
+const MANAGERS_TTL: Duration = Duration::from_secs(60);
+
+/// Maximum number of members returned by one `groups.getMembers` call.
+const GET_MEMBERS_MAX_COUNT: usize = 1000;
+
+/// Managers of communities along with the time they were requested at, by
+/// `group_id`.
+type ManagersCache = Mutex<HashMap<i32, (Instant, Arc<HashSet<Integer>>)>>;
+
+/// Future returned by a [`Guard`].
+pub type GuardFuture<'a> = Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+
+/// Inner type of [`Guard`].
+pub type GuardInner = Arc<dyn for<'a> Fn(&'a Context) -> GuardFuture<'a> + Send + Sync + 'static>;
+
+/// Guard's [`Fn`] should return whether a [`Handler`](crate::Handler) may
+/// handle the event of the given [`Context`]. If it may not, routing falls
+/// through to the next matching handler, as if this one did not match (see
+/// [`Handler::guard`](crate::Handler::guard)).
+///
+/// Guards can be combined using [`Guard::and`], [`Guard::or`] and `!`.
+///
+/// This is essentially a wrapper around
+/// `Arc<dyn Fn(&Context) -> GuardFuture + ...>`.
+#[derive(Clone)]
+pub struct Guard {
+    inner: GuardInner,
+}
+
+impl Guard {
+    /// Creates a new wrapper around a synchronous guard.
+    pub fn new<F>(guard: F) -> Self
+    where
+        F: Fn(&Context) -> bool + Send + Sync + 'static,
+    {
+        Self {
+            inner: Arc::new(move |ctx| Box::pin(future::ready(guard(ctx)))),
+        }
+    }
+
+    /// Creates a new wrapper around an asynchronous guard.
+    ///
+    /// The guard receives its own clone of the [`Context`], so the returned
+    /// future may be `'static`.
+    pub fn new_async<F, Fut>(guard: F) -> Self
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        Self {
+            inner: Arc::new(move |ctx| Box::pin(guard(ctx.clone()))),
+        }
+    }
+
+    /// Allows events from private conversations with users.
+    pub fn private() -> Self {
+        Self::new(|ctx| matches!(ctx.peer_id(), Some(id) if id > 0 && id <= CHAT_PEER_ID_OFFSET))
+    }
+
+    /// Allows events from group chats.
+    pub fn chat() -> Self {
+        Self::new(|ctx| matches!(ctx.peer_id(), Some(id) if id > CHAT_PEER_ID_OFFSET))
+    }
+
+    /// Allows events sent by the given users.
+    pub fn users<I>(ids: I) -> Self
+    where
+        I: IntoIterator<Item = Integer>,
+    {
+        let ids: HashSet<_> = ids.into_iter().collect();
+        Self::new(move |ctx| ctx.sender_id().is_some_and(|id| ids.contains(&id)))
+    }
+
+    /// Allows events sent by managers of the community (see
+    /// [`groups.getMembers`](https://vk.com/dev/groups.getMembers)).
+    ///
+    /// The managers are requested via the API and kept by this guard for a
+    /// minute, separately for every community. If the call fails, the event
+    /// is not allowed.
+    pub fn managers() -> Self {
+        let cache = Arc::new(ManagersCache::default());
+
+        Self {
+            inner: Arc::new(move |ctx| {
+                let cache = Arc::clone(&cache);
+                Box::pin(async move {
+                    let sender = match ctx.sender_id() {
+                        Some(sender) => sender,
+                        None => return false,
+                    };
+
+                    let cached = cache
+                        .lock()
+                        .expect("failed to lock Mutex")
+                        .get(&ctx.group_id())
+                        .filter(|(requested_at, _)| requested_at.elapsed() < MANAGERS_TTL)
+                        .map(|(_, managers)| Arc::clone(managers));
+
+                    let managers = match cached {
+                        Some(managers) => managers,
+                        None => match request_managers(ctx).await {
+                            Some(managers) => {
+                                let managers = Arc::new(managers);
+                                cache.lock().expect("failed to lock Mutex").insert(
+                                    ctx.group_id(),
+                                    (Instant::now(), Arc::clone(&managers)),
+                                );
+                                managers
+                            }
+                            None => return false,
+                        },
+                    };
+
+                    managers.contains(&sender)
+                })
+            }),
+        }
+    }
+
+    /// Allows events sent by admins of the group chat (see
+    /// [`messages.getConversationMembers`](https://vk.com/dev/messages.getConversationMembers)).
+    /// The community must be an admin of the chat.
+    ///
+    /// Every check calls the API. If the call fails, the event is not allowed.
+    pub fn chat_admins() -> Self {
+        Self {
+            inner: Arc::new(|ctx| {
+                Box::pin(async move {
+                    let (peer_id, sender) = match (ctx.peer_id(), ctx.sender_id()) {
+                        (Some(peer_id), Some(sender)) if peer_id > CHAT_PEER_ID_OFFSET => {
+                            (peer_id, sender)
+                        }
+                        _ => return false,
+                    };
+
+                    let mut params = Params::new();
+                    params.insert("peer_id".into(), peer_id.to_string());
+
+                    members(ctx, "messages.getConversationMembers", params, "member_id")
+                        .await
+                        .is_some_and(|(members, _)| {
+                            members.iter().any(|(id, member)| {
+                                *id == sender && member["is_admin"].as_bool() == Some(true)
+                            })
+                        })
+                })
+            }),
+        }
+    }
+
+    /// Returns a guard allowing events allowed by both this guard and `other`.
+    pub fn and(self, other: Guard) -> Self {
+        Self {
+            inner: Arc::new(move |ctx| {
+                let (this, other) = (self.clone(), other.clone());
+                Box::pin(async move { this(ctx).await && other(ctx).await })
+            }),
+        }
+    }
+
+    /// Returns a guard allowing events allowed by either this guard or `other`.
+    pub fn or(self, other: Guard) -> Self {
+        Self {
+            inner: Arc::new(move |ctx| {
+                let (this, other) = (self.clone(), other.clone());
+                Box::pin(async move { this(ctx).await || other(ctx).await })
+            }),
+        }
+    }
+}
+
+impl Not for Guard {
+    type Output = Guard;
+
+    /// Returns a guard allowing events not allowed by this guard.
+    fn not(self) -> Self::Output {
+        Self {
+            inner: Arc::new(move |ctx| {
+                let this = self.clone();
+                Box::pin(async move { !this(ctx).await })
+            }),
+        }
+    }
+}
+
+impl Deref for Guard {
+    type Target = GuardInner;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl Debug for Guard {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str("Guard {...}")
+    }
+}
+
+/// Requests the IDs of the managers of the community of the event, following
+/// all pages of `groups.getMembers`.
+async fn request_managers(ctx: &Context) -> Option<HashSet<Integer>> {
+    let mut managers = HashSet::new();
+    let mut offset = 0;
+
+    loop {
+        let mut params = Params::new();
+        params.insert("group_id".into(), ctx.group_id().to_string());
+        params.insert("filter".into(), "managers".into());
+        params.insert("offset".into(), offset.to_string());
+        params.insert("count".into(), GET_MEMBERS_MAX_COUNT.to_string());
+
+        let (page, count) = members(ctx, "groups.getMembers", params, "id").await?;
+        if page.is_empty() {
+            return Some(managers);
+        }
+
+        offset += page.len();
+        managers.extend(page.into_iter().map(|(id, _)| id));
+        if offset >= count {
+            return Some(managers);
+        }
+    }
+}
+
+/// Calls an API method returning a list of members, and returns their IDs
+/// (taken from the `id_field`) along with the member objects, and the total
+/// number of members.
+async fn members(
+    ctx: &Context,
+    method: &'static str,
+    params: Params,
+    id_field: &str,
+) -> Option<(Vec<(Integer, Value)>, usize)> {
+    let response = match api::call_method(ctx.api(), method, params).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("`{}` failed, guard not passed: {}", method, e);
+            return None;
+        }
+    };
+
+    let items = response["items"].as_array()?;
+    let members = items
+        .iter()
+        .filter_map(|item| Some((item[id_field].as_i64()?, item.clone())))
+        .collect();
+    let count = response["count"]
+        .as_u64()
+        .map_or(items.len(), |count| count as usize);
+
+    Some((members, count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::message_context;
+
+    fn context(peer_id: Integer, from_id: Integer) -> Context {
+        message_context(peer_id, from_id, "hi")
+    }
+
+    #[tokio::test]
+    async fn conversation_type() {
+        let private = context(2, 2);
+        let chat = context(CHAT_PEER_ID_OFFSET + 1, 2);
+
+        assert!(Guard::private()(&private).await);
+        assert!(!Guard::private()(&chat).await);
+        assert!(Guard::chat()(&chat).await);
+        assert!(!Guard::chat()(&private).await);
+    }
+
+    #[tokio::test]
+    async fn users() {
+        let guard = Guard::users(vec![2, 3]);
+
+        assert!(guard(&context(2, 2)).await);
+        assert!(!guard(&context(4, 4)).await);
+    }
+
+    #[tokio::test]
+    async fn combinators() {
+        let ctx = context(CHAT_PEER_ID_OFFSET + 1, 2);
+
+        assert!(Guard::chat().and(Guard::users(vec![2]))(&ctx).await);
+        assert!(!Guard::chat().and(Guard::users(vec![3]))(&ctx).await);
+        assert!(Guard::private().or(Guard::users(vec![2]))(&ctx).await);
+        assert!((!Guard::private())(&ctx).await);
+    }
+}
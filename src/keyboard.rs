@@ -42,21 +42,12 @@ use std::{
 
 /// A keyboard consisting of [`Button`]s that may be shown to the user instead
 /// of the regular keyboard.
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Default, Serialize, Clone)]
 pub struct Keyboard {
     buttons: Vec<Vec<Button>>,
     one_time: bool,
 }
 
-impl Default for Keyboard {
-    fn default() -> Self {
-        Self {
-            buttons: Vec::new(),
-            one_time: false,
-        }
-    }
-}
-
 impl Keyboard {
     /// Creates a new keyboard.
     ///
@@ -108,6 +99,17 @@ impl Button {
         }
     }
 
+    /// Creates a new callback button (see [`Action::Callback`]).
+    pub fn callback(label: &str, color: Color, payload: Option<String>) -> Self {
+        Self {
+            color: Some(color),
+            action: Action::Callback {
+                label: label.into(),
+                payload,
+            },
+        }
+    }
+
     /// Creates a new location-sending button (see [`Action::Location`]).
     pub fn location(payload: Option<String>) -> Self {
         Self {
@@ -169,6 +171,19 @@ pub enum Action {
         payload: Option<String>,
     },
 
+    /// Callback button, type `callback`.
+    ///
+    /// Pressing it does not send a message, but generates an
+    /// [`Event::MessageEvent`](crate::core::Event::MessageEvent), which should
+    /// be answered using [`Context::answer_event`](crate::Context::answer_event).
+    Callback {
+        /// Text shown on the button.
+        label: String,
+        /// Payload that will be sent with the event.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<String>,
+    },
+
     /// Location-sending button, type `location`.
     ///
     /// Always uses full keyboard width.
@@ -204,12 +219,13 @@ pub enum Action {
 }
 
 /// The color of a button.
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Serialize)]
+#[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Color {
     /// `primary` color, `#5181B8`.
     Primary,
     /// `secondary` color, `#FFFFFF`.
+    #[default]
     Secondary,
     /// `negative` color, `#E64646`.
     Negative,
@@ -217,12 +233,6 @@ pub enum Color {
     Positive,
 }
 
-impl Default for Color {
-    fn default() -> Self {
-        Color::Secondary
-    }
-}
-
 impl Display for Color {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         f.write_str(match self {
@@ -277,7 +287,7 @@ mod tests {
         fn test_display_parse(expected_str: &str, expected_color: Color) {
             let color: Color = expected_str
                 .parse()
-                .expect(&format!("could not parse color: `{}`", expected_str));
+                .unwrap_or_else(|_| panic!("could not parse color: `{}`", expected_str));
             assert_eq!(color, expected_color);
             let str = format!("{}", color);
             assert_eq!(str, expected_str);
@@ -356,5 +366,31 @@ mod tests {
 
             Ok(())
         }
+
+        #[test]
+        fn callback() -> Result<(), serde_json::Error> {
+            let kbd = Keyboard::new(
+                vec![vec![
+                    Button::callback("1", Color::Primary, None),
+                    Button::callback("2", Color::Positive, Some(r#"{"a":"b"}"#.into())),
+                ]],
+                false,
+            );
+
+            assert_eq!(
+                serde_json::to_value(&kbd)?,
+                json!({
+                    "buttons":[
+                        [
+                            {"color":"primary","action":{"type":"callback","label":"1"}},
+                            {"color":"positive","action":{"type":"callback","label":"2","payload":r#"{"a":"b"}"#}}
+                        ]
+                    ],
+                    "one_time":false
+                })
+            );
+
+            Ok(())
+        }
     }
 }
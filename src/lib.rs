@@ -1,11 +1,13 @@
 //! Crate for creating chat bots for VK (VKontakte) communities.
 //!
-//! > **note:** this crate requires nightly Rust because of Rocket.
-//!
 //! You can see [`Core`] documentation for information on how to
 //! define bot behavior. In particular, make sure to take a look
 //! at [`Core::on`] first.
 //!
+//! Events can be received either via the Callback API ([`Bot::run`]) or via
+//! the Bots Long Poll API ([`Bot::run_long_poll`]), which does not require a
+//! public URL.
+//!
 //! # Examples
 //! Examples are available in the
 //! [`examples`](https://github.com/u32i64/vk-bot/tree/master/examples)
@@ -16,14 +18,13 @@
 //! # Basic example
 //! The following example is taken from
 //! [`examples/basic.rs`](https://github.com/u32i64/vk-bot/blob/master/examples/basic.rs).
-//! It is not tested as a doc test because [`Bot::start`] never returns.
+//! It is not tested as a doc test because [`Bot::run`] only returns once the
+//! bot is stopped.
 //!
 //! ```ignore
-#![doc(include = "../examples/basic.rs")]
+#![doc = include_str!("../examples/basic.rs")]
 //! ```
 
-#![feature(external_doc)]
-#![feature(proc_macro_hygiene, decl_macro)]
 #![deny(missing_docs)]
 
 #[macro_use]
@@ -33,14 +34,29 @@ extern crate rocket;
 extern crate log;
 
 pub use crate::{
-    bot::Bot,
-    context::Context,
-    core::{Core, Event, Handler, Tester},
+    bot::{BlockingBotHandle, Bot, BotBuilder, BotError, BotHandle, Group},
+    context::{Context, ContextError},
+    core::{
+        ContextErrorHandler, Core, ErrorHandler, Event, Handler, HandlerError, Middleware, Mount,
+        Next, Tester,
+    },
+    guard::Guard,
 };
 
+mod api;
 pub mod bot;
+pub mod command;
 pub mod context;
 pub mod core;
+pub mod dedup;
+pub mod guard;
 pub mod keyboard;
+pub mod longpoll;
+pub mod objects;
 pub mod request;
 pub mod response;
+pub mod session;
+mod state;
+#[cfg(test)]
+mod test_utils;
+pub mod worker;
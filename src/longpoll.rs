@@ -0,0 +1,401 @@
+//! Bots Long Poll API support, an alternative to the Callback API server.
+//!
+//! See [`Bot::run_long_poll`](crate::bot::Bot::run_long_poll) for the
+//! easiest way to use it.
+
+use crate::{api, request::CallbackAPIRequest};
+use reqwest::Client;
+use rvk::{APIClient, Params};
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::{
+    fmt::{Display, Formatter},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
+
+/// Default number of seconds the Long Poll server waits for new events before
+/// responding.
+pub const DEFAULT_WAIT: u64 = 25;
+
+/// Long Poll server information, as returned by `groups.getLongPollServer`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LongPollServer {
+    server: String,
+    key: String,
+    #[serde(deserialize_with = "deserialize_ts")]
+    ts: String,
+}
+
+impl LongPollServer {
+    /// Creates a new [`LongPollServer`].
+    pub fn new(server: &str, key: &str, ts: &str) -> Self {
+        Self {
+            server: server.into(),
+            key: key.into(),
+            ts: ts.into(),
+        }
+    }
+
+    /// Requests the Long Poll server information for the given group using
+    /// `groups.getLongPollServer`.
+    pub async fn fetch(api: &Arc<APIClient>, group_id: i32) -> Result<Self, rvk::error::Error> {
+        let mut params = Params::new();
+        params.insert("group_id".into(), group_id.to_string());
+
+        let value = api::call_method(api, "groups.getLongPollServer", params).await?;
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Returns the server URL.
+    pub fn server(&self) -> &String {
+        &self.server
+    }
+
+    /// Returns the secret session key.
+    pub fn key(&self) -> &String {
+        &self.key
+    }
+
+    /// Returns the number of the last received event.
+    pub fn ts(&self) -> &String {
+        &self.ts
+    }
+}
+
+/// Error type for [`LongPoll::poll`].
+#[derive(Debug)]
+pub enum LongPollError {
+    /// Making the request to the Long Poll server failed.
+    Request(reqwest::Error),
+    /// Long Poll server response could not be parsed.
+    Serde(serde_json::Error),
+    /// Refreshing the server information via the API failed.
+    API(rvk::error::Error),
+    /// Long Poll server responded with an unknown `failed` code.
+    UnknownFailure(i64),
+}
+
+impl Display for LongPollError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            LongPollError::Request(e) => write!(f, "long poll request error: {}", e),
+            LongPollError::Serde(e) => write!(f, "long poll response error: {}", e),
+            LongPollError::API(e) => write!(f, "long poll server refresh error: {}", e),
+            LongPollError::UnknownFailure(code) => {
+                write!(f, "long poll server failed with unknown code {}", code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LongPollError {}
+
+impl From<reqwest::Error> for LongPollError {
+    fn from(e: reqwest::Error) -> Self {
+        LongPollError::Request(e)
+    }
+}
+
+impl From<serde_json::Error> for LongPollError {
+    fn from(e: serde_json::Error) -> Self {
+        LongPollError::Serde(e)
+    }
+}
+
+/// A response of the Long Poll server to an `a_check` request.
+#[derive(Debug, Deserialize)]
+struct CheckResponse {
+    #[serde(default, deserialize_with = "deserialize_opt_ts")]
+    ts: Option<String>,
+    #[serde(default)]
+    updates: Vec<Value>,
+    failed: Option<i64>,
+}
+
+/// A Long Poll session, which keeps track of the server information and
+/// receives events from the server.
+#[derive(Debug)]
+pub struct LongPoll {
+    client: Client,
+    server: LongPollServer,
+    wait: u64,
+}
+
+impl LongPoll {
+    /// Creates a new [`LongPoll`] session using the given server information
+    /// and the [`DEFAULT_WAIT`] time.
+    pub fn new(server: LongPollServer) -> Self {
+        Self::with_wait(server, DEFAULT_WAIT)
+    }
+
+    /// Creates a new [`LongPoll`] session using the given server information,
+    /// waiting at most `wait` seconds for new events on every request.
+    ///
+    /// # Panics
+    /// - if the HTTP client could not be created.
+    pub fn with_wait(server: LongPollServer, wait: u64) -> Self {
+        Self {
+            client: Client::builder()
+                .timeout(Duration::from_secs(wait + 10))
+                .build()
+                .expect("failed to create long poll HTTP client"),
+            server,
+            wait,
+        }
+    }
+
+    /// Returns the current server information.
+    pub fn server(&self) -> &LongPollServer {
+        &self.server
+    }
+
+    /// Makes one `a_check` request and returns received events.
+    ///
+    /// `failed` codes returned by the server are handled as follows:
+    ///
+    /// code | action
+    /// ---|---
+    /// 1 | `ts` is updated
+    /// 2 | `key` is updated using `fetch`
+    /// 3 | `key` and `ts` are updated using `fetch`
+    ///
+    /// No events are returned in these cases; simply call this method again.
+    ///
+    /// Updates which cannot be parsed are logged and skipped, so that they do
+    /// not prevent the following ones from being received.
+    pub async fn poll<F, Fut>(&mut self, fetch: F) -> Result<Vec<CallbackAPIRequest>, LongPollError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<LongPollServer, rvk::error::Error>>,
+    {
+        let wait = self.wait.to_string();
+
+        let text = self
+            .client
+            .get(&self.server.server)
+            .query(&[
+                ("act", "a_check"),
+                ("key", &self.server.key),
+                ("ts", &self.server.ts),
+                ("wait", &wait),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        trace!("long poll response: {}", text);
+
+        let res: CheckResponse = serde_json::from_str(&text)?;
+
+        match res.failed {
+            None => {
+                if let Some(ts) = res.ts {
+                    self.server.ts = ts;
+                }
+                Ok(res
+                    .updates
+                    .into_iter()
+                    .filter_map(|update| {
+                        serde_json::from_value(update)
+                            .map_err(|e| warn!("skipped a long poll update: {}", e))
+                            .ok()
+                    })
+                    .collect())
+            }
+            Some(1) => {
+                debug!("long poll history is outdated, updating `ts`");
+                if let Some(ts) = res.ts {
+                    self.server.ts = ts;
+                }
+                Ok(Vec::new())
+            }
+            Some(2) => {
+                debug!("long poll key has expired, updating `key`");
+                let fresh = fetch().await.map_err(LongPollError::API)?;
+                self.server.key = fresh.key;
+                Ok(Vec::new())
+            }
+            Some(3) => {
+                debug!("long poll information was lost, updating `key` and `ts`");
+                self.server = fetch().await.map_err(LongPollError::API)?;
+                Ok(Vec::new())
+            }
+            Some(code) => Err(LongPollError::UnknownFailure(code)),
+        }
+    }
+}
+
+/// Converts `ts`, which may be sent either as a string or as a number, into a
+/// [`String`].
+fn ts_to_string(value: Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        other => Err(format!("invalid `ts`: {}", other)),
+    }
+}
+
+fn deserialize_ts<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::{de::Error, Deserialize};
+    ts_to_string(Value::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+fn deserialize_opt_ts<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::{de::Error, Deserialize};
+    match Option::<Value>::deserialize(deserializer)? {
+        Some(value) => ts_to_string(value).map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    /// Starts a stand-in Long Poll server which responds with the given bodies
+    /// (one per request, in order) and reports the received request lines.
+    fn stand_in(bodies: Vec<&'static str>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind");
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for body in bodies {
+                let (stream, _) = listener.accept().expect("failed to accept");
+                let mut reader = BufReader::new(stream);
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                tx.send(request_line).unwrap();
+
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        (url, rx)
+    }
+
+    async fn no_fetch() -> Result<LongPollServer, rvk::error::Error> {
+        panic!("fetch should not be called")
+    }
+
+    #[tokio::test]
+    async fn updates() {
+        let (url, rx) = stand_in(vec![
+            r#"{"ts":"11","updates":[{"type":"message_new","object":{"peer_id":1,"text":"hi"},"group_id":1}]}"#,
+        ]);
+        let mut lp = LongPoll::new(LongPollServer::new(&url, "key", "10"));
+
+        let updates = lp.poll(no_fetch).await.expect("poll failed");
+
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].r#type(), "message_new");
+        assert_eq!(updates[0].group_id(), 1);
+        assert_eq!(*updates[0].object().peer_id(), Some(1));
+        assert_eq!(lp.server().ts(), "11");
+
+        let request_line = rx.recv().unwrap();
+        assert!(request_line.contains("act=a_check"));
+        assert!(request_line.contains("key=key"));
+        assert!(request_line.contains("ts=10"));
+    }
+
+    #[tokio::test]
+    async fn skips_malformed_updates() {
+        let (url, _rx) = stand_in(vec![
+            r#"{"ts":"12","updates":[{"type":"message_new","object":[]},{"type":"message_new","object":{"peer_id":1},"group_id":1}]}"#,
+        ]);
+        let mut lp = LongPoll::new(LongPollServer::new(&url, "key", "10"));
+
+        let updates = lp.poll(no_fetch).await.expect("poll failed");
+
+        assert_eq!(updates.len(), 1);
+        assert_eq!(*updates[0].object().peer_id(), Some(1));
+        assert_eq!(lp.server().ts(), "12");
+    }
+
+    #[tokio::test]
+    async fn failed_1_updates_ts() {
+        let (url, _rx) = stand_in(vec![r#"{"failed":1,"ts":30}"#]);
+        let mut lp = LongPoll::new(LongPollServer::new(&url, "key", "10"));
+
+        assert!(lp.poll(no_fetch).await.expect("poll failed").is_empty());
+        assert_eq!(lp.server(), &LongPollServer::new(&url, "key", "30"));
+    }
+
+    #[tokio::test]
+    async fn failed_2_updates_key() {
+        let (url, rx) = stand_in(vec![r#"{"failed":2}"#, r#"{"ts":"11","updates":[]}"#]);
+        let mut lp = LongPoll::new(LongPollServer::new(&url, "old_key", "10"));
+
+        let fresh = LongPollServer::new(&url, "new_key", "99");
+        assert!(lp
+            .poll(|| async { Ok(fresh) })
+            .await
+            .expect("poll failed")
+            .is_empty());
+        assert_eq!(lp.server(), &LongPollServer::new(&url, "new_key", "10"));
+
+        lp.poll(no_fetch).await.expect("poll failed");
+        rx.recv().unwrap();
+        let request_line = rx.recv().unwrap();
+        assert!(request_line.contains("key=new_key"));
+        assert!(request_line.contains("ts=10"));
+    }
+
+    #[tokio::test]
+    async fn failed_3_updates_key_and_ts() {
+        let (url, _rx) = stand_in(vec![r#"{"failed":3}"#]);
+        let mut lp = LongPoll::new(LongPollServer::new(&url, "old_key", "10"));
+
+        let fresh = LongPollServer::new(&url, "new_key", "99");
+        assert!(lp
+            .poll(|| async { Ok(fresh.clone()) })
+            .await
+            .expect("poll failed")
+            .is_empty());
+        assert_eq!(lp.server(), &fresh);
+    }
+
+    #[tokio::test]
+    async fn unknown_failure() {
+        let (url, _rx) = stand_in(vec![r#"{"failed":4}"#]);
+        let mut lp = LongPoll::new(LongPollServer::new(&url, "key", "10"));
+
+        match lp.poll(no_fetch).await {
+            Err(LongPollError::UnknownFailure(4)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
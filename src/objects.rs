@@ -0,0 +1,908 @@
+//! Typed objects of events, see
+//! [`Object::parse`](crate::request::Object::parse) and
+//! [`Context::message`](crate::Context::message).
+
+use rvk::objects::Integer;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt::{Display, Error, Formatter},
+    str::FromStr,
+};
+
+/// A message, the object of
+/// [`Event::MessageNew`](crate::core::Event::MessageNew),
+/// [`Event::MessageReply`](crate::core::Event::MessageReply) and
+/// [`Event::MessageEdit`](crate::core::Event::MessageEdit).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Message {
+    #[serde(default)]
+    id: Integer,
+    date: Integer,
+    peer_id: Option<Integer>,
+    from_id: Integer,
+    #[serde(default)]
+    text: String,
+    conversation_message_id: Option<Integer>,
+    #[serde(default)]
+    fwd_messages: Vec<Message>,
+    reply_message: Option<Box<Message>>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+    geo: Option<Geo>,
+    action: Option<ServiceAction>,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+impl Message {
+    /// Returns the ID of the message, or `0` for messages without one (e.g.
+    /// forwarded messages).
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the date the message was sent (Unix time).
+    pub fn date(&self) -> Integer {
+        self.date
+    }
+
+    /// Returns the ID of the conversation, if present.
+    pub fn peer_id(&self) -> Option<Integer> {
+        self.peer_id
+    }
+
+    /// Returns the ID of the author.
+    pub fn from_id(&self) -> Integer {
+        self.from_id
+    }
+
+    /// Returns the text of the message.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the ID of the message in the conversation, if present.
+    pub fn conversation_message_id(&self) -> Option<Integer> {
+        self.conversation_message_id
+    }
+
+    /// Returns the forwarded messages.
+    pub fn fwd_messages(&self) -> &[Message] {
+        &self.fwd_messages
+    }
+
+    /// Returns the message this message replies to, if any.
+    pub fn reply_message(&self) -> Option<&Message> {
+        self.reply_message.as_deref()
+    }
+
+    /// Returns the attachments of the message.
+    pub fn attachments(&self) -> &[Attachment] {
+        &self.attachments
+    }
+
+    /// Returns the location attached to the message, if any.
+    pub fn geo(&self) -> Option<&Geo> {
+        self.geo.as_ref()
+    }
+
+    /// Returns the service action, if this is a service message.
+    pub fn action(&self) -> Option<&ServiceAction> {
+        self.action.as_ref()
+    }
+
+    /// Returns fields of the message not covered by the methods above.
+    pub fn extra(&self) -> &HashMap<String, Value> {
+        &self.extra
+    }
+}
+
+/// Kinds of [`ServiceAction`]s, see [`Core::on_action`](crate::Core::on_action).
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum ActionKind {
+    /// `chat_invite_user`.
+    ChatInviteUser,
+    /// `chat_invite_user_by_link`.
+    ChatInviteUserByLink,
+    /// `chat_kick_user`.
+    ChatKickUser,
+    /// `chat_title_update`.
+    ChatTitleUpdate,
+    /// `chat_photo_update`.
+    ChatPhotoUpdate,
+    /// `chat_pin_message`.
+    ChatPinMessage,
+    /// `chat_unpin_message`.
+    ChatUnpinMessage,
+    /// `chat_create`.
+    ChatCreate,
+}
+
+impl Display for ActionKind {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.write_str(match self {
+            ActionKind::ChatInviteUser => "chat_invite_user",
+            ActionKind::ChatInviteUserByLink => "chat_invite_user_by_link",
+            ActionKind::ChatKickUser => "chat_kick_user",
+            ActionKind::ChatTitleUpdate => "chat_title_update",
+            ActionKind::ChatPhotoUpdate => "chat_photo_update",
+            ActionKind::ChatPinMessage => "chat_pin_message",
+            ActionKind::ChatUnpinMessage => "chat_unpin_message",
+            ActionKind::ChatCreate => "chat_create",
+        })
+    }
+}
+
+/// Error type for `impl FromStr for ActionKind`.
+#[derive(Debug, Clone, PartialOrd, PartialEq, Eq, Ord)]
+pub struct ActionKindFromStrError(String);
+
+impl Display for ActionKindFromStrError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "unknown action: `{}`", self.0)
+    }
+}
+
+impl FromStr for ActionKind {
+    type Err = ActionKindFromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chat_invite_user" => Ok(ActionKind::ChatInviteUser),
+            "chat_invite_user_by_link" => Ok(ActionKind::ChatInviteUserByLink),
+            "chat_kick_user" => Ok(ActionKind::ChatKickUser),
+            "chat_title_update" => Ok(ActionKind::ChatTitleUpdate),
+            "chat_photo_update" => Ok(ActionKind::ChatPhotoUpdate),
+            "chat_pin_message" => Ok(ActionKind::ChatPinMessage),
+            "chat_unpin_message" => Ok(ActionKind::ChatUnpinMessage),
+            "chat_create" => Ok(ActionKind::ChatCreate),
+
+            _ => Err(ActionKindFromStrError(s.into())),
+        }
+    }
+}
+
+impl TryFrom<&str> for ActionKind {
+    type Error = <ActionKind as FromStr>::Err;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// A service action of a [`Message`], such as a user joining a chat.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum ServiceAction {
+    /// A user was invited to the chat.
+    ChatInviteUser {
+        /// ID of the invited user (negative for communities).
+        member_id: Integer,
+        /// Email of the invited user, if invited by email.
+        email: Option<String>,
+    },
+    /// A user joined the chat using an invite link.
+    ChatInviteUserByLink,
+    /// A user left or was kicked from the chat.
+    ChatKickUser {
+        /// ID of the user.
+        member_id: Integer,
+    },
+    /// The chat title was changed.
+    ChatTitleUpdate {
+        /// The new title.
+        text: String,
+    },
+    /// The chat photo was changed.
+    ChatPhotoUpdate {
+        /// The new photo.
+        photo: Option<Value>,
+    },
+    /// A message was pinned.
+    ChatPinMessage {
+        /// ID of the user who pinned the message.
+        member_id: Integer,
+        /// ID of the pinned message in the conversation.
+        conversation_message_id: Option<Integer>,
+        /// Text of the pinned message.
+        message: Option<String>,
+    },
+    /// A message was unpinned.
+    ChatUnpinMessage {
+        /// ID of the user who unpinned the message.
+        member_id: Integer,
+        /// ID of the unpinned message in the conversation.
+        conversation_message_id: Option<Integer>,
+    },
+    /// The chat was created.
+    ChatCreate {
+        /// Title of the chat.
+        text: String,
+    },
+    /// An action of a type not listed here.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ServiceAction {
+    /// Returns the kind of this action, or `None` for
+    /// [`ServiceAction::Unknown`].
+    pub fn kind(&self) -> Option<ActionKind> {
+        Some(match self {
+            ServiceAction::ChatInviteUser { .. } => ActionKind::ChatInviteUser,
+            ServiceAction::ChatInviteUserByLink => ActionKind::ChatInviteUserByLink,
+            ServiceAction::ChatKickUser { .. } => ActionKind::ChatKickUser,
+            ServiceAction::ChatTitleUpdate { .. } => ActionKind::ChatTitleUpdate,
+            ServiceAction::ChatPhotoUpdate { .. } => ActionKind::ChatPhotoUpdate,
+            ServiceAction::ChatPinMessage { .. } => ActionKind::ChatPinMessage,
+            ServiceAction::ChatUnpinMessage { .. } => ActionKind::ChatUnpinMessage,
+            ServiceAction::ChatCreate { .. } => ActionKind::ChatCreate,
+            ServiceAction::Unknown => return None,
+        })
+    }
+}
+
+/// Information about the features supported by the user's client, sent with
+/// [`Event::MessageNew`](crate::core::Event::MessageNew) in newer API versions.
+/// See [`Context::client_info`](crate::Context::client_info).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClientInfo {
+    #[serde(default)]
+    button_actions: Vec<String>,
+    #[serde(default)]
+    keyboard: bool,
+    #[serde(default)]
+    inline_keyboard: bool,
+    #[serde(default)]
+    carousel: bool,
+    #[serde(default)]
+    lang_id: Integer,
+}
+
+impl ClientInfo {
+    /// Returns the supported button types (see
+    /// [`Action`](crate::keyboard::Action)), e.g. `text` or `callback`.
+    pub fn button_actions(&self) -> &[String] {
+        &self.button_actions
+    }
+
+    /// Returns whether the given button type is supported.
+    pub fn supports_button_action(&self, action: &str) -> bool {
+        self.button_actions.iter().any(|a| a == action)
+    }
+
+    /// Returns whether keyboards are supported.
+    pub fn keyboard(&self) -> bool {
+        self.keyboard
+    }
+
+    /// Returns whether inline keyboards are supported.
+    pub fn inline_keyboard(&self) -> bool {
+        self.inline_keyboard
+    }
+
+    /// Returns whether carousels are supported.
+    pub fn carousel(&self) -> bool {
+        self.carousel
+    }
+
+    /// Returns the ID of the user's language.
+    pub fn lang_id(&self) -> Integer {
+        self.lang_id
+    }
+}
+
+/// An attachment of a [`Message`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Attachment {
+    #[serde(rename = "type")]
+    r#type: String,
+
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+impl Attachment {
+    /// Returns the type of the attachment, e.g. `photo`.
+    pub fn r#type(&self) -> &str {
+        &self.r#type
+    }
+
+    /// Returns the attached object, which is stored in the field named after
+    /// the type.
+    pub fn object(&self) -> Option<&Value> {
+        self.extra.get(&self.r#type)
+    }
+}
+
+/// A location attached to a [`Message`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Geo {
+    #[serde(rename = "type")]
+    r#type: String,
+    coordinates: Coordinates,
+    place: Option<Value>,
+}
+
+impl Geo {
+    /// Returns the type of the location, e.g. `point`.
+    pub fn r#type(&self) -> &str {
+        &self.r#type
+    }
+
+    /// Returns the coordinates.
+    pub fn coordinates(&self) -> Coordinates {
+        self.coordinates
+    }
+
+    /// Returns the description of the place, if any.
+    pub fn place(&self) -> Option<&Value> {
+        self.place.as_ref()
+    }
+}
+
+/// Coordinates of a [`Geo`].
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub struct Coordinates {
+    latitude: f64,
+    longitude: f64,
+}
+
+impl Coordinates {
+    /// Returns the latitude.
+    pub fn latitude(&self) -> f64 {
+        self.latitude
+    }
+
+    /// Returns the longitude.
+    pub fn longitude(&self) -> f64 {
+        self.longitude
+    }
+}
+
+/// A wall post, the object of [`Event::WallPostNew`](crate::core::Event::WallPostNew).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WallPost {
+    id: Integer,
+    owner_id: Integer,
+    from_id: Integer,
+    created_by: Option<Integer>,
+    date: Integer,
+    #[serde(default)]
+    text: String,
+    post_type: Option<String>,
+    #[serde(default)]
+    attachments: Vec<Value>,
+}
+
+impl WallPost {
+    /// Returns the ID of the post.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of the wall owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the ID of the author.
+    pub fn from_id(&self) -> Integer {
+        self.from_id
+    }
+
+    /// Returns the ID of the administrator who published the post on behalf
+    /// of the community, if any.
+    pub fn created_by(&self) -> Option<Integer> {
+        self.created_by
+    }
+
+    /// Returns the publication date (Unix time).
+    pub fn date(&self) -> Integer {
+        self.date
+    }
+
+    /// Returns the text of the post.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the type of the post (`post`, `copy`, `reply`, `postpone` or
+    /// `suggest`).
+    pub fn post_type(&self) -> Option<&str> {
+        self.post_type.as_deref()
+    }
+
+    /// Returns the attachments of the post.
+    pub fn attachments(&self) -> &[Value] {
+        &self.attachments
+    }
+}
+
+/// A comment on a wall post, the object of
+/// [`Event::WallReplyNew`](crate::core::Event::WallReplyNew).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WallReply {
+    id: Integer,
+    from_id: Integer,
+    post_id: Integer,
+    owner_id: Integer,
+    date: Integer,
+    #[serde(default)]
+    text: String,
+    reply_to_user: Option<Integer>,
+    reply_to_comment: Option<Integer>,
+}
+
+impl WallReply {
+    /// Returns the ID of the comment.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of the author.
+    pub fn from_id(&self) -> Integer {
+        self.from_id
+    }
+
+    /// Returns the ID of the post.
+    pub fn post_id(&self) -> Integer {
+        self.post_id
+    }
+
+    /// Returns the ID of the wall owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the publication date (Unix time).
+    pub fn date(&self) -> Integer {
+        self.date
+    }
+
+    /// Returns the text of the comment.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the ID of the user or community this comment replies to, if
+    /// any.
+    pub fn reply_to_user(&self) -> Option<Integer> {
+        self.reply_to_user
+    }
+
+    /// Returns the ID of the comment this comment replies to, if any.
+    pub fn reply_to_comment(&self) -> Option<Integer> {
+        self.reply_to_comment
+    }
+}
+
+/// A new member, the object of [`Event::GroupJoin`](crate::core::Event::GroupJoin).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GroupJoin {
+    user_id: Integer,
+    join_type: String,
+}
+
+impl GroupJoin {
+    /// Returns the ID of the user.
+    pub fn user_id(&self) -> Integer {
+        self.user_id
+    }
+
+    /// Returns how the user joined (`join`, `unsure`, `accepted`, `approved`
+    /// or `request`).
+    pub fn join_type(&self) -> &str {
+        &self.join_type
+    }
+}
+
+/// A member who left, the object of
+/// [`Event::GroupLeave`](crate::core::Event::GroupLeave).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GroupLeave {
+    user_id: Integer,
+    #[serde(rename = "self", default)]
+    by_self: Integer,
+}
+
+impl GroupLeave {
+    /// Returns the ID of the user.
+    pub fn user_id(&self) -> Integer {
+        self.user_id
+    }
+
+    /// Returns whether the user left by themselves, rather than being removed.
+    pub fn by_self(&self) -> bool {
+        self.by_self == 1
+    }
+}
+
+/// A photo, the object of [`Event::PhotoNew`](crate::core::Event::PhotoNew).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Photo {
+    id: Integer,
+    album_id: Integer,
+    owner_id: Integer,
+    user_id: Option<Integer>,
+    date: Integer,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    sizes: Vec<Value>,
+}
+
+impl Photo {
+    /// Returns the ID of the photo.
+    pub fn id(&self) -> Integer {
+        self.id
+    }
+
+    /// Returns the ID of the album.
+    pub fn album_id(&self) -> Integer {
+        self.album_id
+    }
+
+    /// Returns the ID of the owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the ID of the user who uploaded the photo, if it was uploaded
+    /// to a community.
+    pub fn user_id(&self) -> Option<Integer> {
+        self.user_id
+    }
+
+    /// Returns the upload date (Unix time).
+    pub fn date(&self) -> Integer {
+        self.date
+    }
+
+    /// Returns the description of the photo.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the available sizes of the photo.
+    pub fn sizes(&self) -> &[Value] {
+        &self.sizes
+    }
+}
+
+/// A like, the object of [`Event::LikeAdd`](crate::core::Event::LikeAdd) and
+/// [`Event::LikeRemove`](crate::core::Event::LikeRemove).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Like {
+    liker_id: Integer,
+    object_type: String,
+    object_owner_id: Integer,
+    object_id: Integer,
+    post_id: Option<Integer>,
+    thread_reply_id: Option<Integer>,
+}
+
+impl Like {
+    /// Returns the ID of the user who liked the object.
+    pub fn liker_id(&self) -> Integer {
+        self.liker_id
+    }
+
+    /// Returns the type of the object (`video`, `photo`, `post`, `comment`,
+    /// `note`, `topic_comment`, `photo_comment`, `video_comment`, `market` or
+    /// `market_comment`).
+    pub fn object_type(&self) -> &str {
+        &self.object_type
+    }
+
+    /// Returns the ID of the owner of the object.
+    pub fn object_owner_id(&self) -> Integer {
+        self.object_owner_id
+    }
+
+    /// Returns the ID of the object.
+    pub fn object_id(&self) -> Integer {
+        self.object_id
+    }
+
+    /// Returns the ID of the post, if the object is a comment on a post.
+    pub fn post_id(&self) -> Option<Integer> {
+        self.post_id
+    }
+
+    /// Returns the ID of the comment thread, if the object is a reply in one.
+    pub fn thread_reply_id(&self) -> Option<Integer> {
+        self.thread_reply_id
+    }
+}
+
+/// A user added to the blacklist, the object of
+/// [`Event::UserBlock`](crate::core::Event::UserBlock).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UserBlock {
+    admin_id: Integer,
+    user_id: Integer,
+    #[serde(default)]
+    unblock_date: Integer,
+    #[serde(default)]
+    reason: Integer,
+    comment: Option<String>,
+}
+
+impl UserBlock {
+    /// Returns the ID of the administrator who blocked the user.
+    pub fn admin_id(&self) -> Integer {
+        self.admin_id
+    }
+
+    /// Returns the ID of the user.
+    pub fn user_id(&self) -> Integer {
+        self.user_id
+    }
+
+    /// Returns the date the user will be unblocked (Unix time), or `0` if the
+    /// user is blocked permanently.
+    pub fn unblock_date(&self) -> Integer {
+        self.unblock_date
+    }
+
+    /// Returns the reason (`0` — other, `1` — spam, `2` — insulting
+    /// participants, `3` — obscene language, `4` — irrelevant messages).
+    pub fn reason(&self) -> Integer {
+        self.reason
+    }
+
+    /// Returns the comment of the administrator, if any.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+}
+
+/// A user removed from the blacklist, the object of
+/// [`Event::UserUnblock`](crate::core::Event::UserUnblock).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UserUnblock {
+    admin_id: Integer,
+    user_id: Integer,
+    #[serde(default)]
+    by_end_date: Integer,
+}
+
+impl UserUnblock {
+    /// Returns the ID of the administrator who unblocked the user.
+    pub fn admin_id(&self) -> Integer {
+        self.admin_id
+    }
+
+    /// Returns the ID of the user.
+    pub fn user_id(&self) -> Integer {
+        self.user_id
+    }
+
+    /// Returns whether the user was unblocked because the block has expired.
+    pub fn by_end_date(&self) -> bool {
+        self.by_end_date == 1
+    }
+}
+
+/// A poll vote, the object of
+/// [`Event::PollVoteNew`](crate::core::Event::PollVoteNew).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PollVote {
+    owner_id: Integer,
+    poll_id: Integer,
+    option_id: Integer,
+    user_id: Integer,
+}
+
+impl PollVote {
+    /// Returns the ID of the poll owner.
+    pub fn owner_id(&self) -> Integer {
+        self.owner_id
+    }
+
+    /// Returns the ID of the poll.
+    pub fn poll_id(&self) -> Integer {
+        self.poll_id
+    }
+
+    /// Returns the ID of the chosen option.
+    pub fn option_id(&self) -> Integer {
+        self.option_id
+    }
+
+    /// Returns the ID of the user.
+    pub fn user_id(&self) -> Integer {
+        self.user_id
+    }
+}
+
+/// A change of a community manager, the object of
+/// [`Event::GroupOfficersEdit`](crate::core::Event::GroupOfficersEdit).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GroupOfficersEdit {
+    admin_id: Integer,
+    user_id: Integer,
+    level_old: Integer,
+    level_new: Integer,
+}
+
+impl GroupOfficersEdit {
+    /// Returns the ID of the administrator who made the change.
+    pub fn admin_id(&self) -> Integer {
+        self.admin_id
+    }
+
+    /// Returns the ID of the user.
+    pub fn user_id(&self) -> Integer {
+        self.user_id
+    }
+
+    /// Returns the previous level (`0` — none, `1` — moderator, `2` — editor,
+    /// `3` — administrator).
+    pub fn level_old(&self) -> Integer {
+        self.level_old
+    }
+
+    /// Returns the new level, see [`GroupOfficersEdit::level_old`].
+    pub fn level_new(&self) -> Integer {
+        self.level_new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Object;
+
+    fn parse<T: serde::de::DeserializeOwned>(json: &str) -> T {
+        serde_json::from_str::<Object>(json)
+            .expect("failed to deserialize Object")
+            .parse()
+            .expect("failed to parse Object")
+    }
+
+    #[test]
+    fn message() {
+        let message: Message = parse(
+            r#"{"id": 10, "date": 100, "peer_id": 2, "from_id": 2, "text": "hi",
+                "conversation_message_id": 5, "important": false,
+                "fwd_messages": [{"date": 90, "from_id": 3, "text": "fwd"}],
+                "reply_message": {"id": 9, "date": 95, "peer_id": 2, "from_id": 4,
+                                  "text": "reply", "conversation_message_id": 4},
+                "attachments": [{"type": "photo", "photo": {"id": 1}}],
+                "geo": {"type": "point", "coordinates": {"latitude": 1.5, "longitude": 2.5}}}"#,
+        );
+
+        assert_eq!(message.id(), 10);
+        assert_eq!(message.date(), 100);
+        assert_eq!(message.peer_id(), Some(2));
+        assert_eq!(message.text(), "hi");
+        assert_eq!(message.conversation_message_id(), Some(5));
+
+        assert_eq!(message.fwd_messages().len(), 1);
+        assert_eq!(message.fwd_messages()[0].id(), 0);
+        assert_eq!(message.fwd_messages()[0].from_id(), 3);
+        assert_eq!(message.fwd_messages()[0].peer_id(), None);
+
+        let reply = message.reply_message().unwrap();
+        assert_eq!(reply.id(), 9);
+        assert_eq!(reply.text(), "reply");
+
+        assert_eq!(message.attachments()[0].r#type(), "photo");
+        assert_eq!(
+            message.attachments()[0].object(),
+            Some(&serde_json::json!({"id": 1}))
+        );
+
+        let geo = message.geo().unwrap();
+        assert_eq!(geo.r#type(), "point");
+        assert_eq!(geo.coordinates().latitude(), 1.5);
+        assert_eq!(geo.coordinates().longitude(), 2.5);
+
+        assert_eq!(message.extra().get("important"), Some(&Value::Bool(false)));
+    }
+
+    mod action_kind {
+        use super::*;
+
+        fn test_display_parse(expected_str: &str, expected_kind: ActionKind) {
+            let kind: ActionKind = expected_str
+                .parse()
+                .unwrap_or_else(|_| panic!("could not parse action kind: `{}`", expected_str));
+            assert_eq!(kind, expected_kind);
+            let str = format!("{}", kind);
+            assert_eq!(str, expected_str);
+        }
+
+        #[test]
+        fn display_and_parse() {
+            test_display_parse("chat_invite_user", ActionKind::ChatInviteUser);
+            test_display_parse("chat_invite_user_by_link", ActionKind::ChatInviteUserByLink);
+            test_display_parse("chat_kick_user", ActionKind::ChatKickUser);
+            test_display_parse("chat_title_update", ActionKind::ChatTitleUpdate);
+            test_display_parse("chat_photo_update", ActionKind::ChatPhotoUpdate);
+            test_display_parse("chat_pin_message", ActionKind::ChatPinMessage);
+            test_display_parse("chat_unpin_message", ActionKind::ChatUnpinMessage);
+            test_display_parse("chat_create", ActionKind::ChatCreate);
+        }
+
+        #[test]
+        #[should_panic(expected = "unknown action")]
+        fn unknown() {
+            panic!("{}", "foo_bar".parse::<ActionKind>().unwrap_err());
+        }
+    }
+
+    #[test]
+    fn service_action() {
+        let message: Message = parse(
+            r#"{"date": 100, "from_id": 2,
+                "action": {"type": "chat_invite_user", "member_id": 3}}"#,
+        );
+        let action = message.action().unwrap();
+        assert_eq!(
+            action,
+            &ServiceAction::ChatInviteUser {
+                member_id: 3,
+                email: None
+            }
+        );
+        assert_eq!(action.kind(), Some(ActionKind::ChatInviteUser));
+
+        let message: Message = parse(
+            r#"{"date": 100, "from_id": 2,
+                "action": {"type": "chat_screenshot", "member_id": 3}}"#,
+        );
+        assert_eq!(message.action(), Some(&ServiceAction::Unknown));
+        assert_eq!(message.action().unwrap().kind(), None);
+    }
+
+    #[test]
+    fn wall_post() {
+        let post: WallPost = parse(
+            r#"{"id": 5, "owner_id": -1, "from_id": 2, "date": 100, "text": "hi",
+                "post_type": "post", "attachments": [{"type": "photo"}]}"#,
+        );
+
+        assert_eq!(post.id(), 5);
+        assert_eq!(post.owner_id(), -1);
+        assert_eq!(post.from_id(), 2);
+        assert_eq!(post.created_by(), None);
+        assert_eq!(post.text(), "hi");
+        assert_eq!(post.post_type(), Some("post"));
+        assert_eq!(post.attachments().len(), 1);
+    }
+
+    #[test]
+    fn group_leave() {
+        let leave: GroupLeave = parse(r#"{"user_id": 3, "self": 1}"#);
+        assert_eq!(leave.user_id(), 3);
+        assert!(leave.by_self());
+    }
+
+    #[test]
+    fn like() {
+        let like: Like = parse(
+            r#"{"liker_id": 2, "object_type": "comment", "object_owner_id": -1,
+                "object_id": 7, "post_id": 5, "thread_reply_id": 0}"#,
+        );
+
+        assert_eq!(like.liker_id(), 2);
+        assert_eq!(like.object_type(), "comment");
+        assert_eq!(like.post_id(), Some(5));
+    }
+
+    #[test]
+    fn missing_field() {
+        assert!(serde_json::from_str::<Object>(r#"{"user_id": 3}"#)
+            .unwrap()
+            .parse::<GroupJoin>()
+            .is_err());
+    }
+}
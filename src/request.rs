@@ -1,28 +1,65 @@
 //! Structs for storing request information.
 
+use crate::objects::ClientInfo;
 use rvk::objects::Integer;
-use serde_derive::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::{collections::HashMap, convert::TryFrom};
 
 /// A request received from Callback API.
 #[derive(Debug, Deserialize)]
+#[serde(try_from = "RawCallbackAPIRequest")]
 pub struct CallbackAPIRequest {
+    secret: Option<String>,
+    group_id: i32,
+    r#type: String,
+    event_id: Option<String>,
+    object: Object,
+}
+
+/// A [`CallbackAPIRequest`] whose object is not deserialized yet, as its
+/// layout depends on the type of the request.
+#[derive(Deserialize)]
+struct RawCallbackAPIRequest {
     secret: Option<String>,
     group_id: i32,
     #[serde(rename = "type")]
     r#type: String,
+    event_id: Option<String>,
     #[serde(default)]
-    object: Object,
+    object: Value,
+}
+
+impl TryFrom<RawCallbackAPIRequest> for CallbackAPIRequest {
+    type Error = serde_json::Error;
+
+    fn try_from(raw: RawCallbackAPIRequest) -> Result<Self, Self::Error> {
+        let object = deserialize_object(&raw.r#type, raw.object)?;
+        Ok(Self::new(
+            raw.secret,
+            raw.group_id,
+            &raw.r#type,
+            raw.event_id,
+            object,
+        ))
+    }
 }
 
 impl CallbackAPIRequest {
     /// Creates a new [`CallbackAPIRequest`].
-    pub fn new(secret: Option<String>, group_id: i32, r#type: &str, object: Object) -> Self {
+    pub fn new(
+        secret: Option<String>,
+        group_id: i32,
+        r#type: &str,
+        event_id: Option<String>,
+        object: Object,
+    ) -> Self {
         Self {
             secret,
             group_id,
             r#type: r#type.into(),
+            event_id,
             object,
         }
     }
@@ -42,6 +79,13 @@ impl CallbackAPIRequest {
         &self.r#type
     }
 
+    /// Returns the unique ID of the event sent in this request, if present.
+    ///
+    /// Repeated deliveries of the same event have the same ID.
+    pub fn event_id(&self) -> Option<&str> {
+        self.event_id.as_deref()
+    }
+
     /// Returns the [`Object`] sent in this request.
     pub fn object(&self) -> &Object {
         &self.object
@@ -49,33 +93,31 @@ impl CallbackAPIRequest {
 }
 
 /// An object of a [`CallbackAPIRequest`].
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
 pub struct Object {
+    #[serde(skip_serializing_if = "Option::is_none")]
     from_id: Option<Integer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     peer_id: Option<Integer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     user_id: Option<Integer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     text: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_payload",
+        skip_serializing_if = "Option::is_none"
+    )]
     payload: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     action: Option<Value>,
+    #[serde(skip)]
+    client_info: Option<ClientInfo>,
 
     #[serde(flatten)]
     extra: HashMap<String, Value>,
 }
 
-impl Default for Object {
-    fn default() -> Self {
-        Self {
-            from_id: None,
-            peer_id: None,
-            user_id: None,
-            text: None,
-            payload: None,
-            action: None,
-            extra: Default::default(),
-        }
-    }
-}
-
 impl Object {
     /// Creates a new [`Object`].
     pub fn new(
@@ -94,6 +136,7 @@ impl Object {
             text,
             payload,
             action,
+            client_info: None,
             extra,
         }
     }
@@ -119,6 +162,10 @@ impl Object {
     }
 
     /// Returns the payload of this [`Object`].
+    ///
+    /// Payloads sent as JSON values (as in
+    /// [`Event::MessageEvent`](crate::core::Event::MessageEvent)) are returned
+    /// serialized to a string.
     pub fn payload(&self) -> &Option<String> {
         &self.payload
     }
@@ -128,8 +175,145 @@ impl Object {
         &self.action
     }
 
+    /// Returns information about the features supported by the user's client,
+    /// if sent (only with [`Event::MessageNew`](crate::core::Event::MessageNew)
+    /// in newer API versions).
+    pub fn client_info(&self) -> Option<&ClientInfo> {
+        self.client_info.as_ref()
+    }
+
     /// Returns extra fields of this [`Object`].
     pub fn extra(&self) -> &HashMap<String, Value> {
         &self.extra
     }
+
+    /// Parses this [`Object`] as `T`, e.g. one of the structs in the
+    /// [`objects`](crate::objects) module.
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(serde_json::to_value(self)?)
+    }
+}
+
+/// Deserializes an [`Object`] of a request of the given type. `message_new`
+/// objects may be sent either as is, or (in newer API versions) as
+/// `{"message": {...}, "client_info": {...}}`.
+///
+/// If `client_info` is invalid, it is ignored (which is logged).
+fn deserialize_object(r#type: &str, mut value: Value) -> Result<Object, serde_json::Error> {
+    if value.is_null() {
+        return Ok(Default::default());
+    }
+
+    let client_info = match value.get("message") {
+        Some(Value::Object(_)) if r#type == "message_new" => {
+            let client_info = value.get_mut("client_info").map(Value::take);
+            value = value["message"].take();
+            client_info
+        }
+        _ => None,
+    };
+
+    let mut object: Object = serde_json::from_value(value)?;
+    object.client_info = client_info.and_then(|client_info| {
+        serde_json::from_value(client_info)
+            .map_err(|e| warn!("ignored invalid `client_info`: {}", e))
+            .ok()
+    });
+
+    Ok(object)
+}
+
+/// Deserializes a payload, which may be sent either as a string or as a JSON
+/// value, into a [`String`].
+fn deserialize_payload<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    Ok(match Option::<Value>::deserialize(deserializer)? {
+        Some(Value::String(s)) => Some(s),
+        Some(value) => Some(value.to_string()),
+        None => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload() {
+        let string: Object = serde_json::from_str(r#"{"payload": "{\"a\":\"b\"}"}"#).unwrap();
+        let value: Object = serde_json::from_str(r#"{"payload": {"a": "b"}}"#).unwrap();
+        let none: Object = serde_json::from_str("{}").unwrap();
+
+        assert_eq!(string.payload().as_deref(), Some(r#"{"a":"b"}"#));
+        assert_eq!(value.payload().as_deref(), Some(r#"{"a":"b"}"#));
+        assert_eq!(none.payload(), &None);
+    }
+
+    #[test]
+    fn flat_layout() {
+        let req: CallbackAPIRequest = serde_json::from_str(
+            r#"{"type": "message_new", "group_id": 1,
+                "object": {"peer_id": 2, "from_id": 2, "text": "hi"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(req.object().peer_id(), &Some(2));
+        assert_eq!(req.object().text().as_deref(), Some("hi"));
+        assert_eq!(req.object().client_info(), None);
+    }
+
+    #[test]
+    fn nested_layout() {
+        let req: CallbackAPIRequest = serde_json::from_str(
+            r#"{"type": "message_new", "group_id": 1, "object": {
+                "message": {"peer_id": 2, "from_id": 2, "text": "hi", "payload": "{}"},
+                "client_info": {"button_actions": ["text", "callback"], "keyboard": true,
+                                "inline_keyboard": true, "carousel": false, "lang_id": 0}
+            }}"#,
+        )
+        .unwrap();
+
+        let object = req.object();
+        assert_eq!(object.peer_id(), &Some(2));
+        assert_eq!(object.text().as_deref(), Some("hi"));
+        assert_eq!(object.payload().as_deref(), Some("{}"));
+        assert!(object.extra().is_empty());
+
+        let client_info = object.client_info().unwrap();
+        assert_eq!(client_info.button_actions(), ["text", "callback"]);
+        assert!(client_info.supports_button_action("callback"));
+        assert!(client_info.keyboard());
+        assert!(client_info.inline_keyboard());
+        assert!(!client_info.carousel());
+        assert_eq!(client_info.lang_id(), 0);
+    }
+
+    #[test]
+    fn nested_layout_only_for_message_new() {
+        let req: CallbackAPIRequest = serde_json::from_str(
+            r#"{"type": "wall_reply_new", "group_id": 1,
+                "object": {"from_id": 2, "message": {"text": "hi"}}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(req.object().get_from_id(), &Some(2));
+        assert!(req.object().extra().contains_key("message"));
+    }
+
+    #[test]
+    fn invalid_client_info() {
+        let req: CallbackAPIRequest = serde_json::from_str(
+            r#"{"type": "message_new", "group_id": 1, "object": {
+                "message": {"peer_id": 2, "from_id": 2, "text": "hi"},
+                "client_info": {"button_actions": "text"}
+            }}"#,
+        )
+        .unwrap();
+
+        assert_eq!(req.object().text().as_deref(), Some("hi"));
+        assert_eq!(req.object().client_info(), None);
+    }
 }
@@ -1,26 +1,18 @@
 //! Structs for storing response information.
 
 use crate::keyboard::Keyboard;
+use rvk::objects::Integer;
+use serde_derive::Serialize;
 use std::fmt::{Display, Error, Formatter};
 
 /// Manages the bot's current response to a message/event.
-#[derive(Debug)]
+#[derive(Debug, Default, Clone)]
 pub struct Response {
     message: String,
     attachments: Vec<AttachmentInformation>,
     keyboard: Option<Keyboard>,
 }
 
-impl Default for Response {
-    fn default() -> Self {
-        Self {
-            message: String::new(),
-            attachments: Vec::new(),
-            keyboard: None,
-        }
-    }
-}
-
 impl Response {
     /// Creates a new [`Response`].
     pub fn new() -> Self {
@@ -65,7 +57,7 @@ impl Response {
 }
 
 /// Essentially an attachment's unique ID, possibly with an access key.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AttachmentInformation {
     r#type: String,
     owner_id: i64,
@@ -126,3 +118,62 @@ impl AttachmentInformation {
         }
     }
 }
+
+/// An action to perform in response to a callback button press, see
+/// [`Context::answer_event`](crate::Context::answer_event).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum EventAnswer {
+    /// Show a snackbar with the given text (at most 90 characters).
+    ShowSnackbar {
+        /// Text of the snackbar.
+        text: String,
+    },
+    /// Open the given link.
+    OpenLink {
+        /// The link to open.
+        link: String,
+    },
+    /// Open a VK App.
+    OpenApp {
+        /// App identifier.
+        app_id: Integer,
+        /// Group identifier, if the app needs to be opened in the context of a group.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        owner_id: Option<Integer>,
+        /// Hash for navigation inside an app.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hash: Option<String>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn event_answer() -> Result<(), serde_json::Error> {
+        assert_eq!(
+            serde_json::to_value(EventAnswer::ShowSnackbar { text: "hi".into() })?,
+            json!({"type": "show_snackbar", "text": "hi"})
+        );
+        assert_eq!(
+            serde_json::to_value(EventAnswer::OpenLink {
+                link: "https://vk.com".into()
+            })?,
+            json!({"type": "open_link", "link": "https://vk.com"})
+        );
+        assert_eq!(
+            serde_json::to_value(EventAnswer::OpenApp {
+                app_id: 1,
+                owner_id: None,
+                hash: Some("test".into())
+            })?,
+            json!({"type": "open_app", "app_id": 1, "hash": "test"})
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,574 @@
+//! Data kept between events, see [`Context::session`].
+//!
+//! # Example
+//! ```
+//! # use serde_derive::{Deserialize, Serialize};
+//! # use std::time::Duration;
+//! # use vk_bot::{session::{MemorySessionStore, SessionScope}, Core, Handler};
+//! #[derive(Default, Serialize, Deserialize)]
+//! struct Cart {
+//!     items: Vec<String>,
+//! }
+//!
+//! Core::new()
+//!     .sessions(
+//!         MemorySessionStore::with_ttl(Duration::from_secs(3600)),
+//!         SessionScope::User,
+//!     )
+//!     .cmd(
+//!         "add",
+//!         Handler::new(|ctx| {
+//!             let item = ctx.args().unwrap().rest().join(" ");
+//!             ctx.session_mut::<Cart>().items.push(item);
+//!         }),
+//!     );
+//! ```
+
+use crate::context::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fmt::{Debug, Error, Formatter},
+    fs, io,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Storage for sessions, which are JSON values identified by keys.
+pub trait SessionStore: Send + Sync {
+    /// Returns the session stored under `key`, if any.
+    fn load(&self, key: &str) -> Option<Value>;
+
+    /// Stores `value` under `key`, replacing the previous session.
+    fn save(&self, key: &str, value: Value);
+
+    /// Removes the session stored under `key`.
+    fn remove(&self, key: &str);
+
+    /// Persists the sessions saved so far, if they are written in the
+    /// background. Called when a [`Bot`](crate::Bot) shuts down.
+    ///
+    /// Does nothing by default.
+    fn flush(&self) {}
+}
+
+/// In-memory [`SessionStore`], optionally forgetting sessions which were not
+/// saved for some time.
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    ttl: Option<Duration>,
+    inner: Mutex<MemorySessionStoreInner>,
+}
+
+#[derive(Debug, Default)]
+struct MemorySessionStoreInner {
+    sessions: HashMap<String, (Instant, Value)>,
+    purged_at: Option<Instant>,
+}
+
+impl MemorySessionStore {
+    /// Creates a new [`MemorySessionStore`], which keeps sessions forever.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a new [`MemorySessionStore`], which forgets sessions once they
+    /// were not saved for `ttl`.
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            ttl: Some(ttl),
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether a session saved at `saved_at` has expired.
+    fn expired(&self, saved_at: Instant, now: Instant) -> bool {
+        self.ttl
+            .is_some_and(|ttl| now.duration_since(saved_at) >= ttl)
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, key: &str) -> Option<Value> {
+        let inner = self.inner.lock().expect("failed to lock Mutex");
+        match inner.sessions.get(key) {
+            Some((saved_at, value)) if !self.expired(*saved_at, Instant::now()) => {
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn save(&self, key: &str, value: Value) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().expect("failed to lock Mutex");
+
+        // Forget expired sessions at most once per TTL.
+        if let Some(ttl) = self.ttl {
+            if inner
+                .purged_at
+                .is_none_or(|purged_at| now.duration_since(purged_at) >= ttl)
+            {
+                inner
+                    .sessions
+                    .retain(|_, (saved_at, _)| !self.expired(*saved_at, now));
+                inner.purged_at = Some(now);
+            }
+        }
+
+        inner.sessions.insert(key.into(), (now, value));
+    }
+
+    fn remove(&self, key: &str) {
+        self.inner
+            .lock()
+            .expect("failed to lock Mutex")
+            .sessions
+            .remove(key);
+    }
+}
+
+/// [`SessionStore`] keeping sessions in a JSON file, so that they survive
+/// restarts.
+///
+/// Sessions are kept in memory as well; the whole file is rewritten every
+/// time a session is changed, so this store is meant for small bots. Inside
+/// an async runtime, the file is written on a separate thread (see
+/// [`tokio::task::spawn_blocking`]); [`SessionStore::flush`] writes it
+/// immediately.
+#[derive(Debug)]
+pub struct FileSessionStore {
+    inner: Arc<FileSessionStoreInner>,
+}
+
+#[derive(Debug)]
+struct FileSessionStoreInner {
+    path: PathBuf,
+    sessions: Mutex<(Map<String, Value>, u64)>,
+    written: Mutex<u64>,
+}
+
+impl FileSessionStore {
+    /// Opens the file at `path`, loading the sessions from it. The file is
+    /// created once a session is saved, if it does not exist.
+    ///
+    /// # Errors
+    /// - if the file exists, but cannot be read or is not a JSON object.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let sessions = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Map::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            inner: Arc::new(FileSessionStoreInner {
+                path,
+                sessions: Mutex::new((sessions, 0)),
+                written: Mutex::new(0),
+            }),
+        })
+    }
+
+    /// Changes the sessions using `change`, which returns whether they were
+    /// changed, and if so, writes them to the file.
+    fn change<F>(&self, change: F)
+    where
+        F: FnOnce(&mut Map<String, Value>) -> bool,
+    {
+        let (contents, version) = {
+            let mut guard = self.inner.sessions.lock().expect("failed to lock Mutex");
+            let (sessions, version) = &mut *guard;
+            if !change(sessions) {
+                return;
+            }
+
+            *version += 1;
+            (serde_json::to_vec(sessions), *version)
+        };
+
+        let contents = match contents {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("failed to serialize sessions: {}", e);
+                return;
+            }
+        };
+
+        let inner = Arc::clone(&self.inner);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn_blocking(move || inner.write(&contents, version));
+            }
+            Err(_) => inner.write(&contents, version),
+        }
+    }
+}
+
+impl FileSessionStoreInner {
+    /// Writes the given version of the sessions to the file, replacing it
+    /// atomically, unless a newer version is written already.
+    fn write(&self, contents: &[u8], version: u64) {
+        let mut written = self.written.lock().expect("failed to lock Mutex");
+        if *written >= version {
+            return;
+        }
+
+        // Append to the whole file name, so that the temporary file does not
+        // clash with a file of the same stem (`sessions.tmp` for
+        // `sessions.json`).
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let result = fs::write(&tmp, contents).and_then(|()| fs::rename(&tmp, &self.path));
+
+        match result {
+            Ok(()) => *written = version,
+            Err(e) => error!(
+                "failed to write sessions to `{}`: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Writes the latest version of the sessions to the file, unless it is
+    /// written already.
+    fn write_latest(&self) {
+        let (contents, version) = {
+            let guard = self.sessions.lock().expect("failed to lock Mutex");
+            (serde_json::to_vec(&guard.0), guard.1)
+        };
+
+        match contents {
+            Ok(contents) => self.write(&contents, version),
+            Err(e) => error!("failed to serialize sessions: {}", e),
+        }
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, key: &str) -> Option<Value> {
+        let sessions = self.inner.sessions.lock().expect("failed to lock Mutex");
+        sessions.0.get(key).cloned()
+    }
+
+    fn save(&self, key: &str, value: Value) {
+        self.change(|sessions| {
+            if sessions.get(key) == Some(&value) {
+                return false;
+            }
+            sessions.insert(key.into(), value);
+            true
+        });
+    }
+
+    fn remove(&self, key: &str) {
+        self.change(|sessions| sessions.remove(key).is_some());
+    }
+
+    fn flush(&self) {
+        self.inner.write_latest();
+    }
+}
+
+/// Which events share a session, or a state (see
+/// [`Core::state_scope`](crate::Core::state_scope)).
+#[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum SessionScope {
+    /// Events caused by the same user, in any conversation.
+    User,
+    /// Events in the same conversation, caused by any user.
+    Chat,
+    /// Events caused by the same user in the same conversation.
+    #[default]
+    UserInChat,
+}
+
+/// A [`SessionStore`] along with the [`SessionScope`], shared by a
+/// [`Core`](crate::Core) and the [`Context`]s it creates.
+#[derive(Clone)]
+pub(crate) struct Sessions {
+    store: Arc<dyn SessionStore>,
+    scope: SessionScope,
+}
+
+impl Sessions {
+    /// Creates a new [`Sessions`] using the given store and scope.
+    pub(crate) fn new<S>(store: S, scope: SessionScope) -> Self
+    where
+        S: SessionStore + 'static,
+    {
+        Self {
+            store: Arc::new(store),
+            scope,
+        }
+    }
+
+    /// Returns the key of the session of the event, or `None` if the event
+    /// has no user or conversation required by the scope.
+    fn key(&self, ctx: &Context) -> Option<String> {
+        let group_id = ctx.group_id();
+
+        Some(match self.scope {
+            SessionScope::User => format!("{}:user:{}", group_id, ctx.sender_id()?),
+            SessionScope::Chat => format!("{}:peer:{}", group_id, ctx.peer_id()?),
+            SessionScope::UserInChat => format!(
+                "{}:peer:{}:user:{}",
+                group_id,
+                ctx.peer_id()?,
+                ctx.sender_id()?
+            ),
+        })
+    }
+
+    /// Loads the session of the event.
+    pub(crate) fn load<T: DeserializeOwned>(&self, ctx: &Context) -> Option<T> {
+        let key = self.key(ctx)?;
+        let value = self.store.load(&key)?;
+
+        serde_json::from_value(value)
+            .map_err(|e| warn!("failed to deserialize session `{}`: {}", key, e))
+            .ok()
+    }
+
+    /// Loads the session of the event for modification.
+    pub(crate) fn load_mut<T>(&self, ctx: &Context) -> SessionMut<T>
+    where
+        T: Serialize + DeserializeOwned + Default,
+    {
+        let key = self.key(ctx);
+        if key.is_none() {
+            warn!("no user or conversation for the session, it will not be saved");
+        }
+
+        let value = self.load(ctx).unwrap_or_default();
+        SessionMut {
+            loaded: serde_json::to_value(&value).ok(),
+            value,
+            store: Arc::clone(&self.store),
+            key,
+        }
+    }
+
+    /// Removes the session of the event.
+    pub(crate) fn remove(&self, ctx: &Context) {
+        if let Some(key) = self.key(ctx) {
+            self.store.remove(&key);
+        }
+    }
+
+    /// Persists the sessions, see [`SessionStore::flush`].
+    pub(crate) fn flush(&self) {
+        self.store.flush();
+    }
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new(MemorySessionStore::new(), Default::default())
+    }
+}
+
+impl Debug for Sessions {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        write!(f, "Sessions {{ scope: {:?}, ... }}", self.scope)
+    }
+}
+
+/// A session loaded for modification, see [`Context::session_mut`]. The
+/// session is saved when this is dropped, if it was changed.
+pub struct SessionMut<T: Serialize> {
+    value: T,
+    loaded: Option<Value>,
+    store: Arc<dyn SessionStore>,
+    key: Option<String>,
+}
+
+impl<T: Serialize> Deref for SessionMut<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<T: Serialize> DerefMut for SessionMut<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl<T: Serialize> Drop for SessionMut<T> {
+    fn drop(&mut self) {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return,
+        };
+
+        match serde_json::to_value(&self.value) {
+            Ok(value) if Some(&value) == self.loaded.as_ref() => {}
+            Ok(value) => self.store.save(key, value),
+            Err(e) => error!("failed to serialize session `{}`: {}", key, e),
+        }
+    }
+}
+
+impl<T: Serialize + Debug> Debug for SessionMut<T> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        f.debug_struct("SessionMut")
+            .field("value", &self.value)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::message_context;
+    use rvk::objects::Integer;
+    use std::{env, process, thread};
+
+    fn context(sessions: &Sessions, peer_id: Integer, from_id: Integer) -> Context {
+        let mut ctx = message_context(peer_id, from_id, "hi");
+        ctx.set_sessions(sessions.clone());
+        ctx
+    }
+
+    fn count(sessions: &Sessions, peer_id: Integer, from_id: Integer) -> u32 {
+        let ctx = context(sessions, peer_id, from_id);
+        let mut count = ctx.session_mut::<u32>();
+        *count += 1;
+        *count
+    }
+
+    #[test]
+    fn scopes() {
+        let user = Sessions::new(MemorySessionStore::new(), SessionScope::User);
+        let chat = Sessions::new(MemorySessionStore::new(), SessionScope::Chat);
+        let user_in_chat = Sessions::new(MemorySessionStore::new(), SessionScope::UserInChat);
+
+        for sessions in &[&user, &chat, &user_in_chat] {
+            count(sessions, 2_000_000_001, 2);
+        }
+
+        assert_eq!(count(&user, 2_000_000_002, 2), 2);
+        assert_eq!(count(&chat, 2_000_000_001, 3), 2);
+        assert_eq!(count(&user_in_chat, 2_000_000_001, 3), 1);
+        assert_eq!(count(&user_in_chat, 2_000_000_001, 2), 2);
+    }
+
+    #[test]
+    fn clear() {
+        let sessions = Sessions::default();
+        count(&sessions, 2, 2);
+
+        let ctx = context(&sessions, 2, 2);
+        assert_eq!(ctx.session::<u32>(), Some(1));
+        ctx.clear_session();
+        assert_eq!(ctx.session::<u32>(), None);
+    }
+
+    #[test]
+    fn memory_ttl() {
+        let store = MemorySessionStore::with_ttl(Duration::from_millis(50));
+
+        store.save("a", Value::from(1));
+        assert_eq!(store.load("a"), Some(Value::from(1)));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.load("a"), None);
+    }
+
+    #[test]
+    fn file_survives_reopening() {
+        let path = env::temp_dir().join(format!("vk-bot-sessions-{}.json", process::id()));
+        let sibling = path.with_extension("tmp");
+        fs::write(&sibling, "other").unwrap();
+
+        let store = FileSessionStore::open(&path).unwrap();
+        store.save("a", Value::from(1));
+        store.save("b", Value::from(2));
+        store.remove("b");
+        drop(store);
+
+        let store = FileSessionStore::open(&path).unwrap();
+        assert_eq!(store.load("a"), Some(Value::from(1)));
+        assert_eq!(store.load("b"), None);
+        assert_eq!(fs::read_to_string(&sibling).unwrap(), "other");
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&sibling).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn file_written_in_background() {
+        let path = env::temp_dir().join(format!("vk-bot-sessions-async-{}.json", process::id()));
+
+        let store = FileSessionStore::open(&path).unwrap();
+        for i in 0..20 {
+            store.save("a", Value::from(i));
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let reopened = FileSessionStore::open(&path).unwrap();
+        assert_eq!(reopened.load("a"), Some(Value::from(19)));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_flushed() {
+        let path = env::temp_dir().join(format!("vk-bot-sessions-flush-{}.json", process::id()));
+
+        let store = FileSessionStore::open(&path).unwrap();
+        store.save("a", Value::from(1));
+        store.flush();
+
+        let reopened = FileSessionStore::open(&path).unwrap();
+        assert_eq!(reopened.load("a"), Some(Value::from(1)));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saved_only_when_changed() {
+        #[derive(Default)]
+        struct CountingStore {
+            inner: MemorySessionStore,
+            saves: Mutex<u32>,
+        }
+
+        impl SessionStore for CountingStore {
+            fn load(&self, key: &str) -> Option<Value> {
+                self.inner.load(key)
+            }
+
+            fn save(&self, key: &str, value: Value) {
+                *self.saves.lock().unwrap() += 1;
+                self.inner.save(key, value);
+            }
+
+            fn remove(&self, key: &str) {
+                self.inner.remove(key);
+            }
+        }
+
+        let store = Arc::new(CountingStore::default());
+        let sessions = Sessions {
+            store: Arc::clone(&store) as Arc<dyn SessionStore>,
+            scope: SessionScope::UserInChat,
+        };
+
+        let ctx = context(&sessions, 2, 2);
+        drop(ctx.session_mut::<u32>());
+        assert_eq!(*store.saves.lock().unwrap(), 0);
+
+        count(&sessions, 2, 2);
+        drop(ctx.session_mut::<u32>());
+        assert_eq!(*store.saves.lock().unwrap(), 1);
+    }
+}
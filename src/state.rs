@@ -0,0 +1,187 @@
+//! Per-conversation states, see [`Core::state`](crate::Core::state).
+
+use crate::{
+    context::Context,
+    core::{Dispatcher, Handler},
+    response::Response,
+    session::SessionScope,
+};
+use rvk::objects::Integer;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// Identifies the events sharing a state: the group ID, and the peer ID
+/// and the user ID as required by the [`SessionScope`].
+type Key = (i32, Option<Integer>, Option<Integer>);
+
+/// The state of a conversation.
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    expires: Option<Instant>,
+    /// Cancels the timeout of the state once it is changed or cleared.
+    cancel: CancellationToken,
+}
+
+/// States of conversations, shared by a [`Core`](crate::Core) and the
+/// [`Context`]s it creates.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct States {
+    entries: Arc<Mutex<HashMap<Key, Entry>>>,
+    timeouts: Arc<HashMap<String, (Duration, Handler)>>,
+    timers: Arc<OnceLock<(TaskTracker, CancellationToken)>>,
+    dispatcher: Dispatcher,
+    scope: SessionScope,
+}
+
+impl States {
+    /// Runs the timeouts of states on the given tracker, cancelling them once
+    /// `stop` is cancelled. Without this, they run as detached tasks.
+    pub(crate) fn attach(&self, tasks: &TaskTracker, stop: &CancellationToken) {
+        let _ = self.timers.set((tasks.clone(), stop.clone()));
+    }
+
+    /// Sets which events share a state.
+    pub(crate) fn set_scope(&mut self, scope: SessionScope) {
+        self.scope = scope;
+    }
+
+    /// Returns the key of the state of the event, or `None` if the event has
+    /// no user or conversation required by the scope.
+    fn key(&self, ctx: &Context) -> Option<Key> {
+        let group_id = ctx.group_id();
+
+        Some(match self.scope {
+            SessionScope::User => (group_id, None, Some(ctx.sender_id()?)),
+            SessionScope::Chat => (group_id, Some(ctx.peer_id()?), None),
+            SessionScope::UserInChat => (group_id, Some(ctx.peer_id()?), Some(ctx.sender_id()?)),
+        })
+    }
+
+    /// Sets the timeout of a state, after which `handler` is called.
+    pub(crate) fn set_timeout(&mut self, name: &str, timeout: Duration, handler: Handler) {
+        Arc::make_mut(&mut self.timeouts).insert(name.into(), (timeout, handler));
+    }
+
+    /// Returns these states, whose timeout handlers are called using the
+    /// given [`Dispatcher`].
+    pub(crate) fn dispatched_by(&self, dispatcher: Dispatcher) -> Self {
+        Self {
+            dispatcher,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the timeouts of states.
+    pub(crate) fn timeouts(&self) -> &HashMap<String, (Duration, Handler)> {
+        &self.timeouts
+    }
+
+    /// Returns the current state of the event, unless it has expired.
+    pub(crate) fn get(&self, ctx: &Context) -> Option<String> {
+        let key = self.key(ctx)?;
+        let entries = self.entries.lock().expect("failed to lock Mutex");
+        let entry = entries.get(&key)?;
+
+        match entry.expires {
+            Some(expires) if expires <= Instant::now() => None,
+            _ => Some(entry.name.clone()),
+        }
+    }
+
+    /// Sets the state of the event. If the state has a timeout, its handler is
+    /// called with `ctx` once it expires (like other handlers, with the
+    /// middleware and the error handler), unless the state is changed before
+    /// that.
+    pub(crate) fn set(&self, ctx: &Context, name: &str) {
+        let key = match self.key(ctx) {
+            Some(key) => key,
+            None => {
+                warn!(
+                    "attempt to set state `{}` without a user or a conversation",
+                    name
+                );
+                return;
+            }
+        };
+
+        let timeout = self.timeouts.get(name);
+        let cancel = match self.timers.get() {
+            Some((_, stop)) => stop.child_token(),
+            None => CancellationToken::new(),
+        };
+
+        let entry = Entry {
+            name: name.into(),
+            expires: timeout.map(|(timeout, _)| Instant::now() + *timeout),
+            cancel: cancel.clone(),
+        };
+        let mut entries = self.entries.lock().expect("failed to lock Mutex");
+        if let Some(replaced) = entries.insert(key, entry) {
+            replaced.cancel.cancel();
+        }
+        drop(entries);
+
+        let (timeout, handler) = match timeout {
+            Some((timeout, handler)) => (*timeout, handler.clone()),
+            None => return,
+        };
+        let runtime = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => runtime,
+            Err(_) => {
+                warn!(
+                    "no async runtime, timeout of state `{}` will not fire",
+                    name
+                );
+                return;
+            }
+        };
+
+        let entries = Arc::clone(&self.entries);
+        let dispatcher = self.dispatcher.clone();
+        let mut ctx = ctx.clone();
+        *ctx.response() = Response::new();
+        let name = name.to_string();
+
+        let timer = async move {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = tokio::time::sleep(timeout) => {}
+            }
+
+            // The token is cancelled under the lock when the state is changed
+            // or cleared, so the entry is still the one of this timer.
+            {
+                let mut entries = entries.lock().expect("failed to lock Mutex");
+                if cancel.is_cancelled() {
+                    return;
+                }
+                entries.remove(&key);
+            }
+
+            debug!("state `{}` of {:?} expired", name, key);
+            if let Err(e) = dispatcher.call(&handler, &mut ctx).await {
+                error!("timeout handler of state `{}` failed: {}", name, e);
+            }
+        };
+
+        match self.timers.get() {
+            Some((tasks, _)) => runtime.spawn(tasks.track_future(timer)),
+            None => runtime.spawn(timer),
+        };
+    }
+
+    /// Clears the state of the event.
+    pub(crate) fn clear(&self, ctx: &Context) {
+        if let Some(key) = self.key(ctx) {
+            let mut entries = self.entries.lock().expect("failed to lock Mutex");
+            if let Some(removed) = entries.remove(&key) {
+                removed.cancel.cancel();
+            }
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Helpers shared by the tests.
+
+use crate::{
+    context::Context,
+    core::Event,
+    request::{CallbackAPIRequest, Object},
+};
+use rvk::{objects::Integer, APIClient};
+use std::{fmt::Display, sync::Arc};
+
+/// Returns an API client, which is not expected to be called.
+pub(crate) fn api() -> Arc<APIClient> {
+    Arc::new(APIClient::new("vk_token"))
+}
+
+/// Returns an object with the given sender, peer and text.
+pub(crate) fn object(
+    from_id: Option<Integer>,
+    peer_id: Option<Integer>,
+    text: Option<&str>,
+) -> Object {
+    Object::new(
+        from_id,
+        peer_id,
+        None,
+        text.map(Into::into),
+        None,
+        None,
+        Default::default(),
+    )
+}
+
+/// Returns a request of the group `1` for an event of the given type (an
+/// [`Event`](crate::Event) or a string).
+pub(crate) fn request(r#type: impl Display, object: Object) -> CallbackAPIRequest {
+    CallbackAPIRequest::new(None, 1, &r#type.to_string(), None, object)
+}
+
+/// Returns a request for a message with `text` sent by `from_id` to `peer_id`.
+pub(crate) fn message(peer_id: Integer, from_id: Integer, text: &str) -> CallbackAPIRequest {
+    request(
+        Event::MessageNew,
+        object(Some(from_id), Some(peer_id), Some(text)),
+    )
+}
+
+/// Returns the context of a message, see [`message`].
+pub(crate) fn message_context(peer_id: Integer, from_id: Integer, text: &str) -> Context {
+    Context::new(Event::MessageNew, &message(peer_id, from_id, text), api())
+        .expect("failed to create Context")
+}
@@ -0,0 +1,319 @@
+//! Background processing of requests, see
+//! [`BotBuilder::background`](crate::bot::BotBuilder::background).
+
+use crate::{core::Core, request::CallbackAPIRequest};
+use rvk::{objects::Integer, APIClient};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio_util::task::TaskTracker;
+
+/// What to do with a request when the queue of its worker is full.
+#[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// Wait until there is space in the queue before acknowledging the
+    /// request.
+    #[default]
+    Wait,
+    /// Acknowledge the request, but drop it without handling.
+    Drop,
+    /// Respond with `503 Service Unavailable`, so that VK delivers the request
+    /// again later.
+    ///
+    /// With the Bots Long Poll API, updates are never delivered again, so
+    /// this drops the update and logs a warning.
+    Reject,
+}
+
+/// A request waiting to be handled by a worker.
+#[derive(Debug)]
+pub(crate) struct Job {
+    core: Arc<Core>,
+    api: Arc<APIClient>,
+    req: CallbackAPIRequest,
+}
+
+impl Job {
+    /// Creates a new [`Job`].
+    pub(crate) fn new(core: Arc<Core>, api: Arc<APIClient>, req: CallbackAPIRequest) -> Self {
+        Self { core, api, req }
+    }
+
+    /// Returns the key used for choosing the worker: requests with the same
+    /// key are handled in order.
+    fn key(&self) -> Option<(i32, Integer)> {
+        let object = self.req.object();
+        let peer = object
+            .peer_id()
+            .or(*object.user_id())
+            .or(*object.get_from_id())?;
+
+        Some((self.req.group_id(), peer))
+    }
+}
+
+/// A fixed number of workers, each with a bounded queue.
+///
+/// Requests from the same peer always go to the same worker, so they are
+/// handled in order, while requests from different peers are handled in
+/// parallel.
+#[derive(Debug)]
+pub(crate) struct WorkerPool {
+    senders: Vec<Sender<Job>>,
+    receivers: Mutex<Vec<Receiver<Job>>>,
+    policy: QueueFullPolicy,
+    next: AtomicUsize,
+}
+
+impl WorkerPool {
+    /// Creates a new [`WorkerPool`]. Workers are not started until
+    /// [`WorkerPool::start`] is called.
+    ///
+    /// # Panics
+    /// - if `workers` or `capacity` is zero.
+    pub(crate) fn new(workers: usize, capacity: usize, policy: QueueFullPolicy) -> Self {
+        if workers == 0 || capacity == 0 {
+            panic!("number of workers and queue capacity must be greater than zero");
+        }
+
+        let (senders, receivers) = (0..workers).map(|_| mpsc::channel(capacity)).unzip();
+
+        Self {
+            senders,
+            receivers: Mutex::new(receivers),
+            policy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts the workers as tasks tracked by `tasks`. Does nothing if they
+    /// are already started.
+    ///
+    /// Workers stop once this [`WorkerPool`] is dropped and their queues are
+    /// empty.
+    ///
+    /// # Panics
+    /// - if called outside of an async runtime.
+    pub(crate) fn start(&self, tasks: &TaskTracker) {
+        let receivers = std::mem::take(&mut *self.receivers.lock().expect("failed to lock Mutex"));
+
+        for (i, mut rx) in receivers.into_iter().enumerate() {
+            tasks.spawn(async move {
+                while let Some(job) = rx.recv().await {
+                    // Run the handler in a separate task, so that a panic does
+                    // not stop the worker.
+                    let result = tokio::spawn(async move {
+                        job.core.handle(&job.req, &job.api).await;
+                    })
+                    .await;
+
+                    if let Err(e) = result {
+                        error!("request handling failed: {}", e);
+                    }
+                }
+
+                debug!("worker {} stopped", i);
+            });
+        }
+    }
+
+    /// Returns the index of the worker that handles requests with this key.
+    fn worker_index(&self, key: Option<(i32, Integer)>) -> usize {
+        match key {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                (hasher.finish() % self.senders.len() as u64) as usize
+            }
+            None => self.next.fetch_add(1, Ordering::Relaxed) % self.senders.len(),
+        }
+    }
+
+    /// Queues a job according to the [`QueueFullPolicy`]. Returns `false` if
+    /// the job was rejected.
+    pub(crate) async fn enqueue(&self, job: Job) -> bool {
+        let sender = &self.senders[self.worker_index(job.key())];
+
+        let job = match sender.try_send(job) {
+            Ok(()) => return true,
+            Err(TrySendError::Closed(_)) => {
+                warn!("dropped a request, as the worker has stopped");
+                return true;
+            }
+            Err(TrySendError::Full(job)) => job,
+        };
+
+        match self.policy {
+            QueueFullPolicy::Wait => {
+                if sender.send(job).await.is_err() {
+                    warn!("dropped a request, as the worker has stopped");
+                }
+                true
+            }
+            QueueFullPolicy::Drop => {
+                warn!("dropped a request, as the queue is full");
+                true
+            }
+            QueueFullPolicy::Reject => {
+                warn!("rejected a request, as the queue is full");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::Event, request::Object, Handler};
+    use std::{sync::mpsc as std_mpsc, time::Duration};
+
+    /// Returns a job for `peer_id`, whose handler sleeps for `delay_ms`.
+    fn job(core: &Arc<Core>, peer_id: Integer, delay_ms: u64) -> Job {
+        Job::new(
+            Arc::clone(core),
+            Arc::new(APIClient::new("vk_token")),
+            CallbackAPIRequest::new(
+                None,
+                1,
+                &Event::MessageDeny.to_string(),
+                None,
+                Object::new(
+                    None,
+                    Some(peer_id),
+                    None,
+                    Some(delay_ms.to_string()),
+                    None,
+                    None,
+                    Default::default(),
+                ),
+            ),
+        )
+    }
+
+    /// Returns a [`Core`] whose handler sleeps for the number of milliseconds
+    /// in the message text, and then reports the peer ID and the text.
+    fn core(tx: std_mpsc::Sender<(Integer, u64)>) -> Arc<Core> {
+        let tx = Mutex::new(tx);
+
+        Arc::new(Core::new().on(
+            Event::MessageDeny,
+            Handler::new_async(move |ctx| {
+                let tx = tx.lock().unwrap().clone();
+                async move {
+                    let peer_id = ctx.object().peer_id().unwrap();
+                    let delay_ms = ctx.object().text().as_ref().unwrap().parse().unwrap();
+                    tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                    tx.send((peer_id, delay_ms)).unwrap();
+                }
+            }),
+        ))
+    }
+
+    async fn run<F>(pool: WorkerPool, jobs: F) -> Vec<(Integer, u64)>
+    where
+        F: FnOnce(&WorkerPool, &Arc<Core>) -> Vec<Job>,
+    {
+        let (tx, rx) = std_mpsc::channel();
+        let core = core(tx);
+        let tasks = TaskTracker::new();
+        pool.start(&tasks);
+
+        for job in jobs(&pool, &core) {
+            assert!(pool.enqueue(job).await);
+        }
+
+        drop(pool);
+        tasks.close();
+        tasks.wait().await;
+
+        rx.try_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn same_peer_in_order() {
+        let handled = run(WorkerPool::new(4, 8, QueueFullPolicy::Wait), |_, core| {
+            vec![job(core, 1, 100), job(core, 1, 50), job(core, 1, 0)]
+        })
+        .await;
+
+        assert_eq!(handled, vec![(1, 100), (1, 50), (1, 0)]);
+    }
+
+    #[tokio::test]
+    async fn different_peers_in_parallel() {
+        let mut other = 0;
+        let handled = run(
+            WorkerPool::new(4, 8, QueueFullPolicy::Wait),
+            |pool, core| {
+                other = (2..)
+                    .find(|&peer| {
+                        pool.worker_index(Some((1, peer))) != pool.worker_index(Some((1, 1)))
+                    })
+                    .unwrap();
+                vec![job(core, 1, 200), job(core, other, 0)]
+            },
+        )
+        .await;
+
+        assert_eq!(handled, vec![(other, 0), (1, 200)]);
+    }
+
+    async fn fill_queue(policy: QueueFullPolicy) -> (bool, Vec<(Integer, u64)>) {
+        let (tx, rx) = std_mpsc::channel();
+        let core = core(tx);
+        let tasks = TaskTracker::new();
+
+        let pool = WorkerPool::new(1, 1, policy);
+        pool.start(&tasks);
+
+        // The first job is being handled, the second one waits in the queue.
+        assert!(pool.enqueue(job(&core, 1, 100)).await);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.enqueue(job(&core, 2, 0)).await);
+
+        let accepted = pool.enqueue(job(&core, 3, 0)).await;
+
+        drop(pool);
+        tasks.close();
+        tasks.wait().await;
+
+        (accepted, rx.try_iter().collect())
+    }
+
+    #[tokio::test]
+    async fn queue_full_wait() {
+        assert_eq!(
+            fill_queue(QueueFullPolicy::Wait).await,
+            (true, vec![(1, 100), (2, 0), (3, 0)])
+        );
+    }
+
+    #[tokio::test]
+    async fn queue_full_drop() {
+        assert_eq!(
+            fill_queue(QueueFullPolicy::Drop).await,
+            (true, vec![(1, 100), (2, 0)])
+        );
+    }
+
+    #[tokio::test]
+    async fn queue_full_reject() {
+        assert_eq!(
+            fill_queue(QueueFullPolicy::Reject).await,
+            (false, vec![(1, 100), (2, 0)])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "greater than zero")]
+    fn zero_capacity() {
+        WorkerPool::new(1, 0, QueueFullPolicy::Wait);
+    }
+}